@@ -0,0 +1,345 @@
+use crate::parse::PortSpec;
+use crate::paths;
+use anyhow::{Context, Result, bail};
+use iroh::EndpointId;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const AUTHORIZED_KEYS_FILE: &str = "authorized_keys";
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join(AUTHORIZED_KEYS_FILE))
+}
+
+/// What an authorized peer may reach.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Every port exposed by `punch out`.
+    All,
+    /// Only these ports, and only while they are exposed.
+    Ports(HashSet<PortSpec>),
+}
+
+impl Access {
+    fn merge(&mut self, other: Access) {
+        match (&mut *self, other) {
+            (Access::All, _) => {}
+            (_, Access::All) => *self = Access::All,
+            (Access::Ports(ports), Access::Ports(other)) => ports.extend(other),
+        }
+    }
+
+    /// The parts of `port` this access covers, e.g. `10000-10049/udp` of an exposed
+    /// `10000-10100/udp` when only that range is listed.
+    pub fn permitted(&self, port: &PortSpec) -> Vec<PortSpec> {
+        match self {
+            Access::All => vec![port.clone()],
+            Access::Ports(ports) => ports
+                .iter()
+                .filter_map(|allowed| port.intersect(allowed))
+                .collect(),
+        }
+    }
+}
+
+/// The peers allowed to connect to `punch out` and the ports each may use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuthorizedPeers {
+    peers: HashMap<EndpointId, Access>,
+    /// Access for peers without their own entry (`*`).
+    wildcard: Option<Access>,
+    /// The file peers paired through an invitation are appended to.
+    file: Option<PathBuf>,
+}
+
+impl AuthorizedPeers {
+    /// Accepts every peer with access to every exposed port.
+    pub fn any() -> Self {
+        Self {
+            wildcard: Some(Access::All),
+            ..Self::default()
+        }
+    }
+
+    /// Builds the policy from an authorized keys file and `--allow` flags.
+    ///
+    /// An explicitly given `path` must exist. The default file is optional; when it is
+    /// missing and no `--allow` flags are given, every peer is accepted if
+    /// `open_by_default` is set and no peer is accepted otherwise.
+    pub fn load(path: Option<&Path>, allow: &[EndpointId], open_by_default: bool) -> Result<Self> {
+        let (file, contents) = match path {
+            Some(path) => (Some(path.to_path_buf()), Some(read(path)?)),
+            // Without a home directory there is no default file to read or write.
+            None => match default_path() {
+                Ok(path) if path.exists() => {
+                    let contents = read(&path)?;
+                    (Some(path), Some(contents))
+                }
+                Ok(path) => (Some(path), None),
+                Err(_) => (None, None),
+            },
+        };
+
+        let mut authorized = match (&file, contents) {
+            (Some(file), Some(contents)) => parse(&contents)
+                .with_context(|| format!("invalid authorized keys file {}", file.display()))?,
+            _ if allow.is_empty() && open_by_default => return Ok(Self::any()),
+            _ => Self::default(),
+        };
+        authorized.file = file;
+        for peer in allow {
+            authorized.grant(*peer, Access::All);
+        }
+        Ok(authorized)
+    }
+
+    pub fn grant(&mut self, peer: EndpointId, access: Access) {
+        match self.peers.get_mut(&peer) {
+            Some(existing) => existing.merge(access),
+            None => {
+                self.peers.insert(peer, access);
+            }
+        }
+    }
+
+    pub fn accepts_any(&self) -> bool {
+        self.wildcard == Some(Access::All)
+    }
+
+    /// Returns the access granted to `peer`, or `None` if it may not connect.
+    pub fn access(&self, peer: &EndpointId) -> Option<&Access> {
+        self.peers.get(peer).or(self.wildcard.as_ref())
+    }
+
+    pub fn allows(&self, peer: &EndpointId) -> bool {
+        self.access(peer).is_some()
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+}
+
+/// Appends `peer` with access to every exposed port to the authorized keys file at `path`.
+pub fn append(path: &Path, peer: &EndpointId, comment: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("failed to create authorized keys directory")?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open authorized keys file {}", path.display()))?;
+    writeln!(file, "{peer}  # {comment}")
+        .with_context(|| format!("failed to write authorized keys file {}", path.display()))
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .with_context(|| format!("failed to read authorized keys file {}", path.display()))
+}
+
+/// Parses an authorized keys file.
+///
+/// Each line is `<endpoint-id> [grant...]` or `* [grant...]`, where a grant is a port
+/// spec such as `22` or `53/udp`, or an `@group` reference. A line without grants
+/// allows every exposed port. Groups are defined as `@name <port-spec>...`. `#` starts a
+/// comment.
+fn parse(contents: &str) -> Result<AuthorizedPeers> {
+    let lines: Vec<(usize, Vec<&str>)> = contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, strip_comment(line).split_whitespace().collect()))
+        .filter(|(_, tokens): &(usize, Vec<&str>)| !tokens.is_empty())
+        .collect();
+
+    let mut groups: HashMap<&str, HashSet<PortSpec>> = HashMap::new();
+    for (line, tokens) in &lines {
+        let Some(name) = tokens[0].strip_prefix('@') else {
+            continue;
+        };
+        if name.is_empty() {
+            bail!("line {line}: group name must not be empty");
+        }
+        let mut ports = HashSet::new();
+        for token in &tokens[1..] {
+            let port: PortSpec = token
+                .parse()
+                .with_context(|| format!("line {line}: invalid port spec {token:?}"))?;
+            ports.insert(port);
+        }
+        if groups.insert(name, ports).is_some() {
+            bail!("line {line}: duplicate group @{name}");
+        }
+    }
+
+    let mut authorized = AuthorizedPeers::default();
+    for (line, tokens) in &lines {
+        if tokens[0].starts_with('@') {
+            continue;
+        }
+
+        let access = if tokens.len() == 1 {
+            Access::All
+        } else {
+            let mut ports = HashSet::new();
+            for token in &tokens[1..] {
+                match token.strip_prefix('@') {
+                    Some(name) => {
+                        let group = groups
+                            .get(name)
+                            .with_context(|| format!("line {line}: undefined group @{name}"))?;
+                        ports.extend(group.iter().cloned());
+                    }
+                    None => {
+                        let port: PortSpec = token
+                            .parse()
+                            .with_context(|| format!("line {line}: invalid port spec {token:?}"))?;
+                        ports.insert(port);
+                    }
+                }
+            }
+            Access::Ports(ports)
+        };
+
+        match tokens[0] {
+            "*" => match &mut authorized.wildcard {
+                Some(existing) => existing.merge(access),
+                None => authorized.wildcard = Some(access),
+            },
+            peer => {
+                let peer: EndpointId = peer
+                    .parse()
+                    .with_context(|| format!("line {line}: invalid endpoint ID"))?;
+                authorized.grant(peer, access);
+            }
+        }
+    }
+    Ok(authorized)
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.split_once('#') {
+        Some((line, _)) => line.trim(),
+        None => line.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn peer() -> EndpointId {
+        SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    fn ports(specs: &[&str]) -> Access {
+        Access::Ports(specs.iter().map(|spec| spec.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let (a, b) = (peer(), peer());
+        let contents = format!("# team\n\n{a}\n{b}  # laptop\n");
+        let authorized = parse(&contents).unwrap();
+        assert_eq!(authorized.access(&a), Some(&Access::All));
+        assert_eq!(authorized.access(&b), Some(&Access::All));
+        assert_eq!(authorized.access(&peer()), None);
+    }
+
+    #[test]
+    fn parse_reports_line_of_invalid_entry() {
+        let contents = format!("{}\nnot-a-key\n", peer());
+        let err = parse(&contents).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn parse_per_peer_ports_and_groups() {
+        let (ops, contractor) = (peer(), peer());
+        let contents = format!("@ops 22 5432\n{ops} @ops 53/udp\n{contractor} 8080\n");
+        let authorized = parse(&contents).unwrap();
+        assert_eq!(
+            authorized.access(&ops),
+            Some(&ports(&["22", "5432", "53/udp"]))
+        );
+        assert_eq!(authorized.access(&contractor), Some(&ports(&["8080"])));
+    }
+
+    #[test]
+    fn parse_wildcard_applies_to_unlisted_peers() {
+        let (listed, other) = (peer(), peer());
+        let contents = format!("* 8080\n{listed} 22\n");
+        let authorized = parse(&contents).unwrap();
+        assert_eq!(authorized.access(&listed), Some(&ports(&["22"])));
+        assert_eq!(authorized.access(&other), Some(&ports(&["8080"])));
+    }
+
+    #[test]
+    fn parse_rejects_undefined_groups_and_bad_ports() {
+        let contents = format!("{} @missing\n", peer());
+        assert!(
+            parse(&contents)
+                .unwrap_err()
+                .to_string()
+                .contains("@missing")
+        );
+
+        let contents = format!("{} 0\n", peer());
+        assert!(parse(&contents).is_err());
+    }
+
+    #[test]
+    fn repeated_entries_merge_access() {
+        let a = peer();
+        let contents = format!("{a} 22\n{a} 80\n");
+        let authorized = parse(&contents).unwrap();
+        assert_eq!(authorized.access(&a), Some(&ports(&["22", "80"])));
+
+        let contents = format!("{a} 22\n{a}\n");
+        let authorized = parse(&contents).unwrap();
+        assert_eq!(authorized.access(&a), Some(&Access::All));
+    }
+
+    #[test]
+    fn explicit_file_must_exist() {
+        let path = std::env::temp_dir().join("punch-missing-authorized-keys");
+        assert!(AuthorizedPeers::load(Some(&path), &[], true).is_err());
+    }
+
+    #[test]
+    fn allow_flags_restrict_access() {
+        let (a, b) = (peer(), peer());
+        let path = std::env::temp_dir().join(format!("punch-authorized-{a}"));
+        fs::write(&path, "").unwrap();
+        let peers = AuthorizedPeers::load(Some(&path), &[a], true).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(peers.allows(&a));
+        assert!(!peers.allows(&b));
+    }
+
+    #[test]
+    fn appended_peers_are_parsed_back() {
+        let (a, b) = (peer(), peer());
+        let path = std::env::temp_dir()
+            .join(format!("punch-append-{a}"))
+            .join("authorized_keys");
+        append(&path, &a, "paired").unwrap();
+        append(&path, &b, "paired").unwrap();
+
+        let authorized = AuthorizedPeers::load(Some(&path), &[], false).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(authorized.access(&a), Some(&Access::All));
+        assert_eq!(authorized.access(&b), Some(&Access::All));
+        assert_eq!(authorized.file(), Some(path.as_path()));
+    }
+
+    #[test]
+    fn any_allows_every_peer() {
+        assert!(AuthorizedPeers::any().allows(&peer()));
+    }
+}
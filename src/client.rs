@@ -1,79 +1,404 @@
-use crate::parse::{LocalTarget, Mapping, Protocol};
+use crate::control::{
+    self, ALPN_V0, ALPN_V1, Control, FEATURE_OFFER, FEATURE_REVERSE, Hello, Offer,
+};
+use crate::grant::{self, GRANT_ALPN, GRANT_ALPN_V1, Grant};
+use crate::header;
+use crate::invite::{self, PAIR_ALPN};
+use crate::mirror::Mirror;
+use crate::parse::{LocalTarget, Mapping, Protocol, Remote, Reverse, Target};
 use crate::proxy;
+use crate::rejection::Rejection;
+use crate::server;
 use crate::stdio::StdioHandles;
+use crate::stream;
 use crate::udp;
 use anyhow::{Context, Result, bail};
-use iroh::endpoint::Connection;
 use iroh::endpoint::presets;
-use iroh::{Endpoint, EndpointId, SecretKey};
-use std::collections::HashMap;
+use iroh::endpoint::{ConnectOptions, Connection, ConnectionError, RecvStream, SendStream};
+use iroh::{Endpoint, EndpointAddr, EndpointId, SecretKey};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use std::time::{Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::Mutex;
-use tokio::task::JoinSet;
-
-const ALPN: &[u8] = b"punch/0";
+use tokio::task::{JoinHandle, JoinSet};
+
+/// What `punch in` forwards.
+pub struct Forwards {
+    pub mappings: Vec<Mapping>,
+    /// Mirrors every port the remote peer offers, for `--all`. `mappings` is empty then.
+    pub mirror: Option<Mirror>,
+    /// Ports the remote peer listens on and forwards back, for `--reverse`.
+    pub reverse: Vec<Reverse>,
+}
 
 pub async fn run(
     endpoint_id: EndpointId,
-    mappings: Vec<Mapping>,
+    forwards: Forwards,
+    secret_key: SecretKey,
+    invite: Option<String>,
+    grant: Option<String>,
+    report_file: Option<PathBuf>,
+) -> Result<()> {
+    let Forwards {
+        mappings,
+        mirror,
+        reverse,
+    } = forwards;
+    if let Some(token) = &grant {
+        Grant::decode(token)?
+            .check(&endpoint_id, &secret_key.public(), SystemTime::now())
+            .context("grant cannot be used for this connection")?;
+    }
+
+    let endpoint = Endpoint::builder(presets::N0)
+        .secret_key(secret_key)
+        .bind()
+        .await?;
+
+    if let Some(code) = invite {
+        pair(&endpoint, endpoint_id, &code).await?;
+        eprintln!("paired with {endpoint_id}");
+    }
+
+    let mut session = connect(&endpoint, endpoint_id, grant.as_deref()).await?;
+    let peer = session.peer();
+    session.bind_reverse(&reverse).await?;
+    if let Some(mirror) = mirror {
+        let control = session
+            .control
+            .take()
+            .context("remote peer is too old to list what it offers")?;
+        tokio::select! {
+            result = run_mirror(session.conn.clone(), peer, control, mirror, report_file) => return result,
+            result = run_reverse(session.conn.clone(), reverse) => return result,
+            result = shutdown_signal() => return result,
+        }
+    }
+    peer.check_mappings(&mappings)?;
+    if let Some(offer) = session.offer().await? {
+        offer.check_mappings(&mappings)?;
+    }
+    let bound = bind_mappings(mappings).await?;
+    report_bound(&bound, report_file.as_deref())?;
+    let conn = session.conn.clone();
+    let forward = async {
+        if bound.is_empty() {
+            // Only --reverse was given, which runs until the connection ends.
+            return Err(connection_lost(conn.closed().await));
+        }
+        run_connection(conn.clone(), peer, bound).await
+    };
+    // Returning on SIGINT or SIGTERM drops the listeners, which removes their socket files.
+    tokio::select! {
+        result = forward => result,
+        result = run_reverse(session.conn.clone(), reverse) => result,
+        result = shutdown_signal() => result,
+    }
+}
+
+/// Runs `punch ls`, printing what the remote peer lets this endpoint reach.
+pub async fn list(
+    endpoint_id: EndpointId,
     secret_key: SecretKey,
+    grant: Option<String>,
 ) -> Result<()> {
     let endpoint = Endpoint::builder(presets::N0)
         .secret_key(secret_key)
         .bind()
         .await?;
+    let mut session = connect(&endpoint, endpoint_id, grant.as_deref()).await?;
+    let offer = session
+        .offer()
+        .await?
+        .context("remote peer is too old to list what it offers")?;
+    print!("{offer}");
+    session.conn.close(0u32.into(), b"done");
+    endpoint.close().await;
+    Ok(())
+}
+
+async fn shutdown_signal() -> Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => {}
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// A connection to `punch out`, with its control stream on `punch/1`.
+pub(crate) struct Session {
+    pub(crate) conn: Connection,
+    /// `None` for `punch/0` peers, which have no control stream.
+    pub(crate) control: Option<Control>,
+}
+
+impl Session {
+    async fn start(conn: Connection) -> Result<Self> {
+        if conn.alpn() != ALPN_V1 && conn.alpn() != GRANT_ALPN_V1 {
+            return Ok(Self {
+                conn,
+                control: None,
+            });
+        }
+        match control::open(&conn).await {
+            Ok(control) => Ok(Self {
+                conn,
+                control: Some(control),
+            }),
+            // The server closes the connection of a peer it rejects without a reply.
+            Err(e) => Err(conn.close_reason().map_or(e, connection_lost)),
+        }
+    }
+
+    /// What both sides support.
+    pub(crate) fn peer(&self) -> Hello {
+        self.control
+            .as_ref()
+            .map_or(Hello::V0, |control| control.negotiated)
+    }
+
+    /// Asks what the remote peer offers this endpoint. `None` if it cannot say.
+    pub(crate) async fn offer(&mut self) -> Result<Option<Offer>> {
+        match &mut self.control {
+            Some(control) if control.negotiated.supports(FEATURE_OFFER) => {
+                control.offer().await.map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Asks the remote peer to listen on the remote port of each `--reverse` mapping.
+    pub(crate) async fn bind_reverse(&mut self, reverse: &[Reverse]) -> Result<()> {
+        if reverse.is_empty() {
+            return Ok(());
+        }
+        let control = match &mut self.control {
+            Some(control) if control.negotiated.supports(FEATURE_REVERSE) => control,
+            _ => bail!("remote peer is too old for --reverse"),
+        };
+        for reverse in reverse {
+            control
+                .bind(reverse.remote)
+                .await
+                .with_context(|| format!("cannot use --reverse {reverse}"))?;
+            eprintln!(
+                "remote peer listening on port {} for local port {}",
+                reverse.remote, reverse.local
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the remote peer, presenting `grant` first when one is given. `punch/1` is
+/// preferred, and `punch/0` is used with peers that do not support it.
+pub(crate) async fn connect(
+    endpoint: &Endpoint,
+    addr: impl Into<EndpointAddr>,
+    grant: Option<&str>,
+) -> Result<Session> {
+    let Some(token) = grant else {
+        let options = ConnectOptions::new().with_additional_alpns(vec![ALPN_V0.to_vec()]);
+        let conn = endpoint
+            .connect_with_opts(addr, ALPN_V1, options)
+            .await?
+            .await?;
+        return Session::start(conn).await;
+    };
+
+    let options = ConnectOptions::new().with_additional_alpns(vec![GRANT_ALPN.to_vec()]);
+    let conn = endpoint
+        .connect_with_opts(addr, GRANT_ALPN_V1, options)
+        .await?
+        .await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    if let Err(e) = grant::present(&mut send, &mut recv, token).await {
+        conn.close(0u32.into(), b"done");
+        return Err(e);
+    }
+    Session::start(conn).await
+}
+
+/// Redeems an invitation so the remote peer adds this endpoint to its allowlist.
+pub(crate) async fn pair(
+    endpoint: &Endpoint,
+    addr: impl Into<EndpointAddr>,
+    code: &str,
+) -> Result<()> {
+    let conn = endpoint
+        .connect(addr, PAIR_ALPN)
+        .await
+        .context("remote peer is not accepting invitations")?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    let result = invite::present(&mut send, &mut recv, code).await;
+    conn.close(0u32.into(), b"done");
+    result
+}
+
+/// A mapping whose local listener or socket is bound.
+pub(crate) struct BoundMapping {
+    mapping: Mapping,
+    local: BoundLocal,
+}
+
+enum BoundLocal {
+    Tcp(TcpListener),
+    Unix(UnixListener, SocketFile),
+    Udp(Arc<UdpSocket>),
+    Stdio,
+}
+
+impl BoundMapping {
+    /// The address the mapping listens on, including the port the OS picked for
+    /// `0:<remote>`. Stdio mappings have none.
+    pub(crate) fn local_addr(&self) -> Result<Option<String>> {
+        let addr = match &self.local {
+            BoundLocal::Tcp(listener) => listener.local_addr()?.to_string(),
+            BoundLocal::Udp(socket) => socket.local_addr()?.to_string(),
+            BoundLocal::Unix(_, SocketFile(path)) => format!("unix:{}", path.display()),
+            BoundLocal::Stdio => return Ok(None),
+        };
+        Ok(Some(addr))
+    }
+}
+
+/// Binds the local side of every mapping before forwarding starts.
+pub(crate) async fn bind_mappings(mappings: Vec<Mapping>) -> Result<Vec<BoundMapping>> {
+    let mut bound = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        let local = match (&mapping.local, mapping.protocol) {
+            (&LocalTarget::Addr(local_addr), Protocol::Tcp) => {
+                BoundLocal::Tcp(TcpListener::bind(local_addr).await?)
+            }
+            (&LocalTarget::Addr(local_addr), Protocol::Udp) => {
+                BoundLocal::Udp(Arc::new(UdpSocket::bind(local_addr).await?))
+            }
+            (LocalTarget::Unix(path), Protocol::Tcp) => {
+                let (listener, socket_file) = bind_unix(path).await?;
+                BoundLocal::Unix(listener, socket_file)
+            }
+            (LocalTarget::Unix(_), Protocol::Udp) => {
+                unreachable!("udp unix socket mappings are rejected during parsing")
+            }
+            (LocalTarget::Stdio, _) => BoundLocal::Stdio,
+        };
+        bound.push(BoundMapping { mapping, local });
+    }
+    Ok(bound)
+}
+
+/// The local address and remote of each bound mapping, e.g. `127.0.0.1:3000` and
+/// `8080/tcp`. Stdio mappings have no local address and are left out.
+fn bound_addrs(bound: &[BoundMapping]) -> Result<Vec<(String, String)>> {
+    let mut addrs = Vec::with_capacity(bound.len());
+    for bound in bound {
+        let Some(local) = bound.local_addr()? else {
+            continue;
+        };
+        let remote = format!(
+            "{}/{}",
+            bound.mapping.remote,
+            bound.mapping.protocol.suffix()
+        );
+        addrs.push((local, remote));
+    }
+    Ok(addrs)
+}
+
+/// Prints the address each mapping listens on to stderr and, when `report_file` is given,
+/// writes them there as `<local>\t<remote>/<proto>` lines.
+fn report_bound(bound: &[BoundMapping], report_file: Option<&Path>) -> Result<()> {
+    let addrs = bound_addrs(bound)?;
+    for (local, remote) in &addrs {
+        eprintln!("listening on {local} for {remote}");
+    }
+    match report_file {
+        Some(path) => write_report(path, &addrs),
+        None => Ok(()),
+    }
+}
 
-    let conn = endpoint.connect(endpoint_id, ALPN).await?;
-    run_connection(conn, mappings).await
+fn write_report(path: &Path, addrs: &[(String, String)]) -> Result<()> {
+    let report: String = addrs
+        .iter()
+        .map(|(local, remote)| format!("{local}\t{remote}\n"))
+        .collect();
+    // Renaming a complete file means readers never see a partial report.
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, report)
+        .with_context(|| format!("failed to write report file {}", path.display()))?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("failed to write report file {}", path.display()))?;
+    Ok(())
 }
 
-pub(crate) async fn run_connection(conn: Connection, mappings: Vec<Mapping>) -> Result<()> {
-    let stdio = mappings
+/// Forwards the bound mappings over `conn`. `peer` is what was negotiated with the
+/// remote peer.
+pub(crate) async fn run_connection(
+    conn: Connection,
+    peer: Hello,
+    bound: Vec<BoundMapping>,
+) -> Result<()> {
+    let stdio = bound
         .iter()
-        .any(|mapping| mapping.local == LocalTarget::Stdio)
+        .any(|bound| matches!(bound.local, BoundLocal::Stdio))
         .then(StdioHandles::from_process_stdio)
         .transpose()?;
 
-    run_connection_with_stdio(conn, mappings, stdio).await
+    run_connection_with_stdio(conn, peer, bound, stdio).await
 }
 
 async fn run_connection_with_stdio(
     conn: Connection,
-    mappings: Vec<Mapping>,
+    peer: Hello,
+    bound: Vec<BoundMapping>,
     mut stdio: Option<StdioHandles>,
 ) -> Result<()> {
     let mut tasks = JoinSet::new();
     let mut udp_mappings = Vec::new();
 
-    for mapping in mappings {
-        match (mapping.local, mapping.protocol) {
-            (LocalTarget::Port(_), Protocol::Tcp) => {
+    for BoundMapping { mapping, local } in bound {
+        match local {
+            BoundLocal::Tcp(listener) => {
+                let conn = conn.clone();
+                tasks.spawn(async move { run_tcp_listener(conn, listener, mapping.remote).await });
+            }
+            BoundLocal::Unix(listener, socket_file) => {
                 let conn = conn.clone();
-                tasks.spawn(async move { run_listener(conn, mapping).await });
+                tasks.spawn(async move {
+                    run_unix_listener(conn, listener, socket_file, mapping.remote).await
+                });
             }
-            (LocalTarget::Port(local_port), Protocol::Udp) => {
-                let socket = Arc::new(UdpSocket::bind(("127.0.0.1", local_port)).await?);
+            BoundLocal::Udp(socket) => {
+                let Remote::Target(remote) = mapping.remote else {
+                    unreachable!("udp mappings to named services are rejected during parsing")
+                };
                 udp_mappings.push(UdpMappingState {
-                    local_port,
-                    remote_port: mapping.remote,
+                    local_addr: socket.local_addr()?,
+                    remote,
                     socket,
+                    max_payload: usize::from(peer.max_datagram_size),
                 });
             }
-            (LocalTarget::Stdio, Protocol::Tcp) => {
+            BoundLocal::Stdio => {
                 let conn = conn.clone();
                 let stdio = stdio.take().context("missing stdio handles")?;
                 tasks.spawn(async move { run_stdio_mapping(conn, mapping.remote, stdio).await });
             }
-            (LocalTarget::Stdio, Protocol::Udp) => {
-                unreachable!("udp stdio mappings are rejected during parsing")
-            }
         }
     }
-
     if !udp_mappings.is_empty() {
         let udp_mappings = Arc::new(udp_mappings);
         let state = Arc::new(Mutex::new(ClientUdpState::default()));
@@ -96,32 +421,304 @@ async fn run_connection_with_stdio(
     supervise_tasks(conn.closed(), tasks).await
 }
 
-async fn run_listener(conn: Connection, mapping: Mapping) -> Result<()> {
-    let LocalTarget::Port(local_port) = mapping.local else {
-        bail!("stdio mappings are not supported yet");
-    };
+/// A task forwarding mirrored mappings. It is aborted when dropped, which closes its
+/// listeners when `punch in --all` stops.
+struct MirrorTask(JoinHandle<()>);
+
+impl MirrorTask {
+    /// Aborts the task and waits for it, so its ports can be bound again.
+    async fn stop(mut self) {
+        self.0.abort();
+        let _ = (&mut self.0).await;
+    }
+}
+
+impl Drop for MirrorTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Mirrors every port the remote peer offers onto the local ports `mirror` picks, adding
+/// and removing listeners as the offer changes. Ports that cannot be bound are reported
+/// and skipped. The report file, if any, is rewritten after every change.
+async fn run_mirror(
+    conn: Connection,
+    peer: Hello,
+    mut control: Control,
+    mut mirror: Mirror,
+    report_file: Option<PathBuf>,
+) -> Result<()> {
+    control.watch().await?;
+    // The local and remote address of each TCP mapping, and the task accepting on it.
+    let mut tcp: HashMap<Mapping, ((String, String), MirrorTask)> = HashMap::new();
+    // UDP mappings share the connection's datagrams, so they run as one group that is
+    // restarted when the offered set changes. Their sockets are kept for mappings that
+    // stay. Mappings that failed to bind are only retried once the set changes, so they
+    // do not restart the working ones on every offer.
+    let mut udp: HashMap<Mapping, Arc<UdpSocket>> = HashMap::new();
+    let mut udp_offered: HashSet<Mapping> = HashSet::new();
+    let mut udp_task: Option<MirrorTask> = None;
+
+    loop {
+        let offer = tokio::select! {
+            offer = control.next_offer() => offer?,
+            reason = conn.closed() => return Err(connection_lost(reason)),
+        };
+        let mirrored = mirror.mappings(&offer);
+        for (protocol, port) in mirrored.unmapped {
+            eprintln!(
+                "no local port left for {port}/{}, not mirroring it",
+                protocol.suffix()
+            );
+        }
+        let (tcp_wanted, udp_wanted): (Vec<_>, Vec<_>) = mirrored
+            .mappings
+            .into_iter()
+            .partition(|mapping| mapping.protocol == Protocol::Tcp);
+
+        let removed: Vec<Mapping> = tcp
+            .keys()
+            .filter(|mapping| !tcp_wanted.contains(mapping))
+            .cloned()
+            .collect();
+        for mapping in removed {
+            let ((local, remote), task) = tcp.remove(&mapping).expect("listener is running");
+            task.stop().await;
+            eprintln!("stopped listening on {local} for {remote}");
+        }
+
+        let offered: HashSet<Mapping> = udp_wanted.iter().cloned().collect();
+        if offered != udp_offered {
+            udp_offered = offered;
+            if let Some(task) = udp_task.take() {
+                task.stop().await;
+            }
+            let mut sockets = HashMap::with_capacity(udp_wanted.len());
+            let mut bound = Vec::with_capacity(udp_wanted.len());
+            for mapping in udp_wanted {
+                let socket = match udp.remove(&mapping) {
+                    Some(socket) => socket,
+                    None => match bind_mirrored(&mapping).await {
+                        Some(BoundLocal::Udp(socket)) => socket,
+                        _ => continue,
+                    },
+                };
+                sockets.insert(mapping.clone(), socket.clone());
+                bound.push(BoundMapping {
+                    mapping,
+                    local: BoundLocal::Udp(socket),
+                });
+            }
+            // What is left are the mappings that are no longer offered.
+            for (mapping, socket) in udp {
+                let local = socket.local_addr()?;
+                eprintln!("stopped listening on {local} for {}/udp", mapping.remote);
+            }
+            udp = sockets;
+            if !bound.is_empty() {
+                let conn = conn.clone();
+                udp_task = Some(MirrorTask(tokio::spawn(async move {
+                    if let Err(e) = run_connection_with_stdio(conn, peer, bound, None).await {
+                        eprintln!("udp forwarding error: {e}");
+                    }
+                })));
+            }
+        }
+
+        for mapping in tcp_wanted {
+            if tcp.contains_key(&mapping) {
+                continue;
+            }
+            let Some(BoundLocal::Tcp(listener)) = bind_mirrored(&mapping).await else {
+                continue;
+            };
+            let addrs = (
+                listener.local_addr()?.to_string(),
+                format!("{}/tcp", mapping.remote),
+            );
+            let conn = conn.clone();
+            let remote = mapping.remote.clone();
+            let task = MirrorTask(tokio::spawn(async move {
+                if let Err(e) = run_tcp_listener(conn, listener, remote).await {
+                    eprintln!("listener error: {e}");
+                }
+            }));
+            tcp.insert(mapping, (addrs, task));
+        }
+
+        if let Some(path) = &report_file {
+            let mut addrs: Vec<(String, String)> =
+                tcp.values().map(|(addrs, _)| addrs.clone()).collect();
+            for (mapping, socket) in &udp {
+                addrs.push((
+                    socket.local_addr()?.to_string(),
+                    format!("{}/udp", mapping.remote),
+                ));
+            }
+            addrs.sort();
+            write_report(path, &addrs)?;
+        }
+    }
+}
+
+/// Binds one mirrored mapping, reporting rather than failing when its port is taken.
+async fn bind_mirrored(mapping: &Mapping) -> Option<BoundLocal> {
+    match bind_mappings(vec![mapping.clone()]).await {
+        Ok(mut bound) => {
+            let bound = bound.pop()?;
+            if let Ok(Some(local)) = bound.local_addr() {
+                eprintln!(
+                    "listening on {local} for {}/{}",
+                    mapping.remote,
+                    mapping.protocol.suffix()
+                );
+            }
+            Some(bound.local)
+        }
+        Err(e) => {
+            eprintln!(
+                "failed to listen for {}/{}: {e}",
+                mapping.remote,
+                mapping.protocol.suffix()
+            );
+            None
+        }
+    }
+}
 
-    let listener = TcpListener::bind(("127.0.0.1", local_port)).await?;
+async fn run_tcp_listener(conn: Connection, listener: TcpListener, remote: Remote) -> Result<()> {
     loop {
         let (tcp, _) = listener.accept().await?;
-        let conn = conn.clone();
+        stream::spawn(&conn, &remote, tcp);
+    }
+}
+
+/// Accepts connections until the task ends, which drops `_socket_file` and removes it.
+async fn run_unix_listener(
+    conn: Connection,
+    listener: UnixListener,
+    _socket_file: SocketFile,
+    remote: Remote,
+) -> Result<()> {
+    loop {
+        let (unix, _) = listener.accept().await?;
+        stream::spawn(&conn, &remote, unix);
+    }
+}
+
+/// Serves the streams the remote peer opens for connections to its `--reverse` ports,
+/// connecting each to the local port it is mapped to.
+pub(crate) async fn run_reverse(conn: Connection, reverse: Vec<Reverse>) -> Result<()> {
+    let local: Arc<HashMap<u16, u16>> = Arc::new(
+        reverse
+            .into_iter()
+            .map(|reverse| (reverse.remote, reverse.local))
+            .collect(),
+    );
+    loop {
+        let (send, recv) = conn.accept_bi().await.map_err(connection_lost)?;
+        let local = local.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_stream(conn, mapping.remote, tcp).await {
-                eprintln!("stream error: {e}");
+            if let Err(e) = handle_reverse_stream(send, recv, &local).await {
+                eprintln!("reverse stream error: {e:#}");
             }
         });
     }
 }
 
-async fn handle_stream(conn: Connection, remote_port: u16, tcp: TcpStream) -> Result<()> {
-    let (mut send, recv) = conn.open_bi().await?;
-    send.write_all(&remote_port.to_be_bytes()).await?;
+async fn handle_reverse_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    local: &HashMap<u16, u16>,
+) -> Result<()> {
+    let remote = match header::read(&mut recv).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            stream::reset(&mut send, &mut recv, Rejection::Failed);
+            return Err(e);
+        }
+    };
+    let port = match &remote {
+        Remote::Target(Target::Inet { host: None, ports }) => {
+            ports.single_port().and_then(|port| local.get(&port))
+        }
+        _ => None,
+    };
+    let Some(&port) = port else {
+        stream::reset(&mut send, &mut recv, Rejection::NotAllowed);
+        bail!("{remote} is not a --reverse port");
+    };
+    let connect = TcpStream::connect((Ipv4Addr::LOCALHOST, port));
+    let tcp = stream::connect_or_reset(&mut send, &mut recv, connect).await?;
     proxy::bidirectional(send, recv, tcp).await
 }
 
-async fn run_stdio_mapping(conn: Connection, remote_port: u16, stdio: StdioHandles) -> Result<()> {
+/// Removes a Unix socket file created by `punch in` when dropped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Binds a Unix socket that only the current user may connect to, replacing a stale
+/// socket left behind by an earlier run.
+async fn bind_unix(path: &Path) -> Result<(UnixListener, SocketFile)> {
+    remove_stale_socket(path).await?;
+    // The socket is created with the permissions the umask leaves. It is bound and
+    // restricted inside a directory only we can enter, and only then moved into place, so
+    // others cannot connect in between.
+    let dir = private_dir(path)?;
+    let listener = bind_restricted(&dir.join("s"), path);
+    let _ = fs::remove_dir_all(&dir);
+    let listener =
+        listener.with_context(|| format!("failed to bind unix socket {}", path.display()))?;
+    Ok((listener, SocketFile(path.to_path_buf())))
+}
+
+/// Creates a directory only the current user can enter, next to `path`. Its name is kept
+/// short, as socket paths are limited to about a hundred bytes.
+fn private_dir(path: &Path) -> Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = parent.join(format!(".punch-{:08x}", rand::rng().random::<u32>()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create a directory in {}", parent.display()))?;
+    Ok(dir)
+}
+
+fn bind_restricted(temporary: &Path, path: &Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(temporary)?;
+    fs::set_permissions(temporary, fs::Permissions::from_mode(0o600))?;
+    fs::rename(temporary, path)?;
+    Ok(listener)
+}
+
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{} already exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("{} is in use by another process", path.display());
+    }
+    fs::remove_file(path)
+        .with_context(|| format!("failed to remove stale socket {}", path.display()))
+}
+
+async fn run_stdio_mapping(conn: Connection, remote: Remote, stdio: StdioHandles) -> Result<()> {
     let (mut send, mut recv) = conn.open_bi().await?;
-    send.write_all(&remote_port.to_be_bytes()).await?;
+    header::write(&mut send, &remote).await?;
 
     let StdioHandles {
         mut input,
@@ -130,14 +727,18 @@ async fn run_stdio_mapping(conn: Connection, remote_port: u16, stdio: StdioHandl
     } = stdio;
     let _raw_mode_guard = raw_mode_guard;
 
-    proxy::bridge(&mut send, &mut recv, &mut input, &mut output).await
+    proxy::bridge(&mut send, &mut recv, &mut input, &mut output)
+        .await
+        .map_err(|e| stream::explain_rejection(e, &remote))
 }
 
 #[derive(Clone)]
 struct UdpMappingState {
-    local_port: u16,
-    remote_port: u16,
+    local_addr: SocketAddr,
+    remote: Target,
     socket: Arc<UdpSocket>,
+    /// Largest payload the remote peer accepts.
+    max_payload: usize,
 }
 
 #[derive(Default)]
@@ -233,6 +834,13 @@ async fn run_udp_mapping(
                 continue;
             }
         };
+        if len > mapping.max_payload {
+            eprintln!(
+                "udp datagram of {len} bytes exceeds the remote peer's limit of {} bytes",
+                mapping.max_payload
+            );
+            continue;
+        }
 
         let flow_id = {
             let mut state = state.lock().await;
@@ -240,16 +848,15 @@ async fn run_udp_mapping(
                 Some(flow_id) => flow_id,
                 None => {
                     eprintln!(
-                        "udp flow table exhausted for local port {}",
-                        mapping.local_port
+                        "udp flow table exhausted for local address {}",
+                        mapping.local_addr
                     );
                     continue;
                 }
             }
         };
 
-        if let Err(e) = udp::send_client_datagram(&conn, flow_id, mapping.remote_port, &buf[..len])
-        {
+        if let Err(e) = udp::send_client_datagram(&conn, flow_id, &mapping.remote, &buf[..len]) {
             eprintln!("udp datagram error: {e}");
         }
     }
@@ -296,9 +903,12 @@ async fn run_udp_cleanup(state: Arc<Mutex<ClientUdpState>>) -> Result<()> {
     }
 }
 
-async fn supervise_tasks(conn_closed: impl Future, mut tasks: JoinSet<Result<()>>) -> Result<()> {
+async fn supervise_tasks(
+    conn_closed: impl Future<Output = ConnectionError>,
+    mut tasks: JoinSet<Result<()>>,
+) -> Result<()> {
     tokio::select! {
-        _ = conn_closed => bail!("connection to remote peer lost"),
+        reason = conn_closed => Err(connection_lost(reason)),
         result = tasks.join_next() => match result {
             Some(result) => result?,
             None => Ok(()),
@@ -306,24 +916,81 @@ async fn supervise_tasks(conn_closed: impl Future, mut tasks: JoinSet<Result<()>
     }
 }
 
+fn connection_lost(reason: ConnectionError) -> anyhow::Error {
+    match reason {
+        ConnectionError::ApplicationClosed(close)
+            if close.error_code == server::UNAUTHORIZED_CLOSE_CODE.into() =>
+        {
+            anyhow::anyhow!("remote peer rejected this endpoint as unauthorized")
+        }
+        _ => anyhow::anyhow!("connection to remote peer lost"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ClientUdpState, run_connection, run_connection_with_stdio, supervise_tasks};
-    use crate::parse::{Mapping, PortSpec};
-    use crate::server::{self, AllowedPorts};
+    use super::{
+        BoundMapping, ClientUdpState, bind_mappings, bind_unix, connect, pair, report_bound,
+        run_connection, run_connection_with_stdio, run_mirror, run_reverse, supervise_tasks,
+    };
+    use crate::authorized::AuthorizedPeers;
+    use crate::control::{ALPN_V0, Hello};
+    use crate::grant::Grant;
+    use crate::header;
+    use crate::invite::{Invites, PAIR_ALPN};
+    use crate::mirror::{LocalPorts, Mirror};
+    use crate::parse::{Exposure, Mapping, PortRange, PortSpec, Protocol, Remote, Reverse, Target};
+    use crate::policy::Policy;
+    use crate::rejection::Rejection;
+    use crate::server;
     use crate::stdio::StdioHandles;
     use crate::udp;
-    use anyhow::Result;
-    use iroh::endpoint::presets;
+    use anyhow::{Context, Result};
+    use iroh::endpoint::{ConnectionError, ReadError, presets};
     use iroh::{Endpoint, SecretKey};
     use std::net::{Ipv4Addr, SocketAddr};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
     use std::time::{Duration, Instant};
     use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
-    use tokio::net::{TcpListener, TcpStream, UdpSocket};
-    use tokio::sync::oneshot;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+    use tokio::sync::{oneshot, watch};
     use tokio::task::JoinSet;
     use tokio::time::{sleep, timeout};
 
+    /// The port the OS picked for a `0:<remote>` mapping.
+    fn local_port(bound: &BoundMapping) -> Result<u16> {
+        let addr: SocketAddr = bound.local_addr()?.context("no local address")?.parse()?;
+        Ok(addr.port())
+    }
+
+    #[tokio::test]
+    async fn ephemeral_ports_are_reported() -> Result<()> {
+        let mappings = ["0:8080", "0:53/udp", "-:22"]
+            .iter()
+            .map(|mapping| mapping.parse())
+            .collect::<Result<Vec<Mapping>>>()?;
+        let bound = bind_mappings(mappings).await?;
+        let tcp_port = local_port(&bound[0])?;
+        let udp_port = local_port(&bound[1])?;
+        assert_ne!(tcp_port, 0);
+        assert_ne!(udp_port, 0);
+        assert_eq!(bound[2].local_addr()?, None);
+
+        let path = std::env::temp_dir().join(format!(
+            "punch-report-{}",
+            SecretKey::generate(&mut rand::rng()).public()
+        ));
+        report_bound(&bound, Some(&path))?;
+        let report = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            report,
+            format!("127.0.0.1:{tcp_port}\t8080/tcp\n127.0.0.1:{udp_port}\t53/udp\n")
+        );
+        Ok(())
+    }
+
     async fn spawn_tcp_echo_server() -> Result<(u16, tokio::task::JoinHandle<()>)> {
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let port = listener.local_addr()?.port();
@@ -340,12 +1007,12 @@ mod tests {
     }
 
     async fn spawn_remote_server(
-        allowed: AllowedPorts,
+        policy: Policy,
     ) -> Result<(Endpoint, tokio::task::JoinHandle<()>)> {
         let server_key = SecretKey::generate(&mut rand::rng());
         let server_endpoint = Endpoint::builder(presets::N0)
             .secret_key(server_key)
-            .alpns(vec![super::ALPN.to_vec()])
+            .alpns(vec![ALPN_V0.to_vec()])
             .bind()
             .await?;
 
@@ -354,7 +1021,39 @@ mod tests {
             tokio::spawn(async move {
                 let incoming = server_endpoint.accept().await.unwrap();
                 let conn = incoming.await.unwrap();
-                let _ = server::serve_connection(conn, allowed).await;
+                let _ = server::serve_connection(
+                    conn,
+                    watch::Sender::new(Arc::new(policy)).subscribe(),
+                    None,
+                    None,
+                )
+                .await;
+            })
+        };
+
+        Ok((server_endpoint, task))
+    }
+
+    /// Serves every incoming connection like `punch out`, including pairing and grants.
+    async fn spawn_stateful_server(
+        server_key: SecretKey,
+        state: Arc<server::ServerState>,
+    ) -> Result<(Endpoint, tokio::task::JoinHandle<()>)> {
+        let server_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(server_key)
+            .alpns(server::alpns(true))
+            .bind()
+            .await?;
+
+        let task = {
+            let server_endpoint = server_endpoint.clone();
+            tokio::spawn(async move {
+                while let Some(incoming) = server_endpoint.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let _ = server::handle_connection(incoming, &state).await;
+                    });
+                }
             })
         };
 
@@ -385,7 +1084,7 @@ mod tests {
 
         let result = timeout(
             Duration::from_secs(1),
-            supervise_tasks(std::future::pending::<()>(), tasks),
+            supervise_tasks(std::future::pending::<ConnectionError>(), tasks),
         )
         .await;
 
@@ -426,17 +1125,23 @@ mod tests {
         let server_key = SecretKey::generate(&mut rand::rng());
         let server_endpoint = Endpoint::builder(presets::N0)
             .secret_key(server_key)
-            .alpns(vec![super::ALPN.to_vec()])
+            .alpns(vec![ALPN_V0.to_vec()])
             .bind()
             .await?;
 
-        let allowed = AllowedPorts::from_ports(&[format!("{echo_port}/udp").parse::<PortSpec>()?]);
+        let policy = Policy::open(&[format!("{echo_port}/udp").parse::<PortSpec>()?]);
 
         let server_addr = server_endpoint.addr();
         let server_task = tokio::spawn(async move {
             let incoming = server_endpoint.accept().await.unwrap();
             let conn = incoming.await.unwrap();
-            let _ = server::serve_connection(conn, allowed).await;
+            let _ = server::serve_connection(
+                conn,
+                watch::Sender::new(Arc::new(policy)).subscribe(),
+                None,
+                None,
+            )
+            .await;
         });
 
         let client_key = SecretKey::generate(&mut rand::rng());
@@ -445,15 +1150,13 @@ mod tests {
             .bind()
             .await?;
 
-        let conn = client_endpoint.connect(server_addr, super::ALPN).await?;
-
-        let probe = UdpSocket::bind("127.0.0.1:0").await?;
-        let local_port = probe.local_addr()?.port();
-        drop(probe);
+        let conn = client_endpoint.connect(server_addr, ALPN_V0).await?;
 
-        let mapping: Mapping = format!("{local_port}:{echo_port}/udp").parse()?;
+        let mapping: Mapping = format!("0:{echo_port}/udp").parse()?;
+        let bound = bind_mappings(vec![mapping]).await?;
+        let local_port = local_port(&bound[0])?;
         let client_task = tokio::spawn(async move {
-            let _ = run_connection(conn, vec![mapping]).await;
+            let _ = run_connection(conn, Hello::V0, bound).await;
         });
 
         sleep(Duration::from_millis(100)).await;
@@ -486,12 +1189,53 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn udp_flow_switching_target_drops_the_old_target() -> Result<()> {
+        let old_target = UdpSocket::bind("127.0.0.1:0").await?;
+        let new_target = UdpSocket::bind("127.0.0.1:0").await?;
+        let old_port = old_target.local_addr()?.port();
+        let new_port = new_target.local_addr()?.port();
+        let (server_endpoint, server_task) = spawn_remote_server(Policy::open(&[
+            format!("{old_port}/udp").parse()?,
+            format!("{new_port}/udp").parse()?,
+        ]))
+        .await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let mut buf = [0u8; 64];
+        udp::send_client_datagram(&conn, 1, &Target::loopback(old_port), b"old")?;
+        let (_, old_flow) =
+            timeout(Duration::from_secs(5), old_target.recv_from(&mut buf)).await??;
+        udp::send_client_datagram(&conn, 1, &Target::loopback(new_port), b"new")?;
+        let (_, new_flow) =
+            timeout(Duration::from_secs(5), new_target.recv_from(&mut buf)).await??;
+
+        old_target.send_to(b"stale", old_flow).await?;
+        new_target.send_to(b"fresh", new_flow).await?;
+        let mut replies = Vec::new();
+        while let Ok(datagram) = timeout(Duration::from_millis(500), conn.read_datagram()).await {
+            let datagram = datagram?;
+            replies.push(udp::decode_server_datagram(&datagram)?.payload.to_vec());
+        }
+        assert_eq!(replies, [b"fresh".to_vec()]);
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        Ok(())
+    }
+
     #[tokio::test]
     async fn stdio_mapping_roundtrips_bytes() -> Result<()> {
         let (remote_port, echo_task) = spawn_tcp_echo_server().await?;
-        let allowed =
-            AllowedPorts::from_ports(&[format!("{remote_port}/tcp").parse::<PortSpec>()?]);
-        let (server_endpoint, server_task) = spawn_remote_server(allowed).await?;
+        let policy = Policy::open(&[format!("{remote_port}/tcp").parse::<PortSpec>()?]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
 
         let client_key = SecretKey::generate(&mut rand::rng());
         let client_endpoint = Endpoint::builder(presets::N0)
@@ -499,7 +1243,7 @@ mod tests {
             .bind()
             .await?;
         let conn = client_endpoint
-            .connect(server_endpoint.addr(), super::ALPN)
+            .connect(server_endpoint.addr(), ALPN_V0)
             .await?;
 
         let mapping: Mapping = "-:22".replace("22", &remote_port.to_string()).parse()?;
@@ -508,7 +1252,13 @@ mod tests {
         let stdio = StdioHandles::from_parts(input_reader, output_writer);
 
         let client_task = tokio::spawn(async move {
-            run_connection_with_stdio(conn, vec![mapping], Some(stdio)).await
+            run_connection_with_stdio(
+                conn,
+                Hello::V0,
+                bind_mappings(vec![mapping]).await?,
+                Some(stdio),
+            )
+            .await
         });
 
         input_writer.write_all(b"stdio-test").await?;
@@ -529,72 +1279,320 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn stdio_mapping_errors_when_remote_port_is_refused() -> Result<()> {
-        let allowed = AllowedPorts::from_ports(&[]);
-        let (server_endpoint, server_task) = spawn_remote_server(allowed).await?;
+    async fn named_service_resolves_on_the_server() -> Result<()> {
+        let (echo_port, echo_task) = spawn_tcp_echo_server().await?;
+        let exposure: Exposure = format!("echo={echo_port}").parse()?;
+        let policy = Policy::new(vec![exposure], AuthorizedPeers::any());
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
 
-        let client_key = SecretKey::generate(&mut rand::rng());
         let client_endpoint = Endpoint::builder(presets::N0)
-            .secret_key(client_key)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
             .bind()
             .await?;
         let conn = client_endpoint
-            .connect(server_endpoint.addr(), super::ALPN)
+            .connect(server_endpoint.addr(), ALPN_V0)
             .await?;
 
-        let mapping: Mapping = "-:9999".parse()?;
-        let (input_writer, input_reader) = duplex(64);
-        drop(input_writer);
-        let (output_writer, _output_reader) = duplex(64);
-        let stdio = StdioHandles::from_parts(input_reader, output_writer);
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Service("echo".into())).await?;
+        send.write_all(b"by-name").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"by-name");
 
-        let result = run_connection_with_stdio(conn, vec![mapping], Some(stdio)).await;
-        assert!(result.is_err());
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Service("db".into())).await?;
+        send.finish()?;
+        assert!(recv.read_to_end(4096).await.is_err());
 
         client_endpoint.close().await;
         server_endpoint.close().await;
         server_task.abort();
-        let _ = server_task.await;
+        echo_task.abort();
         Ok(())
     }
 
     #[tokio::test]
-    async fn stdio_mapping_can_run_alongside_listener_mappings() -> Result<()> {
-        let (stdio_remote_port, stdio_echo_task) = spawn_tcp_echo_server().await?;
-        let (listener_remote_port, listener_echo_task) = spawn_tcp_echo_server().await?;
-        let local_listener_probe = TcpListener::bind("127.0.0.1:0").await?;
-        let local_listener_port = local_listener_probe.local_addr()?.port();
-        drop(local_listener_probe);
-
-        let allowed = AllowedPorts::from_ports(&[
-            format!("{stdio_remote_port}/tcp").parse::<PortSpec>()?,
-            format!("{listener_remote_port}/tcp").parse::<PortSpec>()?,
-        ]);
-        let (server_endpoint, server_task) = spawn_remote_server(allowed).await?;
+    async fn host_targets_are_matched_in_full() -> Result<()> {
+        let (echo_port, echo_task) = spawn_tcp_echo_server().await?;
+        let target: Target = format!("127.0.0.1:{echo_port}").parse()?;
+        let policy = Policy::open(&[PortSpec {
+            target: target.clone(),
+            protocol: Protocol::Tcp,
+        }]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
 
-        let client_key = SecretKey::generate(&mut rand::rng());
         let client_endpoint = Endpoint::builder(presets::N0)
-            .secret_key(client_key)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
             .bind()
             .await?;
         let conn = client_endpoint
-            .connect(server_endpoint.addr(), super::ALPN)
+            .connect(server_endpoint.addr(), ALPN_V0)
             .await?;
 
-        let stdio_mapping: Mapping = format!("-:{stdio_remote_port}").parse()?;
-        let listener_mapping: Mapping =
-            format!("{local_listener_port}:{listener_remote_port}").parse()?;
-        let (mut input_writer, input_reader) = duplex(64);
-        let (output_writer, mut output_reader) = duplex(64);
-        let stdio = StdioHandles::from_parts(input_reader, output_writer);
-
-        let client_task = tokio::spawn(async move {
-            run_connection_with_stdio(conn, vec![stdio_mapping, listener_mapping], Some(stdio))
-                .await
-        });
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Target(target)).await?;
+        send.write_all(b"via-host").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"via-host");
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Target(Target::loopback(echo_port))).await?;
+        send.finish()?;
+        assert!(recv.read_to_end(4096).await.is_err());
+
+        // A range is never connected to one of its ports.
+        let range: Target = format!("127.0.0.1:{}-{echo_port}", echo_port - 1).parse()?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Target(range)).await?;
+        let mut buf = [0u8; 1];
+        let Err(ReadError::Reset(code)) = recv.read(&mut buf).await else {
+            panic!("stream to a port range was not reset");
+        };
+        assert_eq!(Rejection::from_code(code), Rejection::NotAllowed);
 
-        input_writer.write_all(b"stdio-live").await?;
-        sleep(Duration::from_millis(100)).await;
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn destinations_are_checked_against_rules() -> Result<()> {
+        let (echo_port, echo_task) = spawn_tcp_echo_server().await?;
+        let rule = format!("127.0.0.0/8:{echo_port}").parse()?;
+        let policy =
+            Policy::new(Vec::<PortSpec>::new(), AuthorizedPeers::any()).with_dest_rules(vec![rule]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        for target in [
+            format!("127.0.0.1:{echo_port}"),
+            format!("localhost:{echo_port}"),
+        ] {
+            let (mut send, mut recv) = conn.open_bi().await?;
+            header::write(&mut send, &Remote::Target(target.parse()?)).await?;
+            send.write_all(b"chosen").await?;
+            send.finish()?;
+            assert_eq!(recv.read_to_end(4096).await?, b"chosen");
+        }
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let other_port = if echo_port == 65535 { 1 } else { echo_port + 1 };
+        let target: Target = format!("127.0.0.1:{other_port}").parse()?;
+        header::write(&mut send, &Remote::Target(target)).await?;
+        send.finish()?;
+        assert!(recv.read_to_end(4096).await.is_err());
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn named_unix_socket_is_bridged() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "punch-test-{}.sock",
+            SecretKey::generate(&mut rand::rng()).public()
+        ));
+        let listener = UnixListener::bind(&path)?;
+        let echo_task = tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut read, mut write) = socket.split();
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                });
+            }
+        });
+
+        let exposure: Exposure = format!("echo=unix:{}", path.display()).parse()?;
+        let policy = Policy::new(vec![exposure], AuthorizedPeers::any());
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        header::write(&mut send, &Remote::Service("echo".into())).await?;
+        send.write_all(b"over-unix").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"over-unix");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Runs a stdio mapping to `remote` against a server exposing `exposed`, returning why
+    /// the server rejected it.
+    async fn stdio_rejection(exposed: &[PortSpec], remote: &str) -> Result<Rejection> {
+        let (server_endpoint, server_task) = spawn_remote_server(Policy::open(exposed)).await?;
+
+        let client_key = SecretKey::generate(&mut rand::rng());
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(client_key)
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let mapping: Mapping = format!("-:{remote}").parse()?;
+        let (input_writer, input_reader) = duplex(64);
+        drop(input_writer);
+        let (output_writer, _output_reader) = duplex(64);
+        let stdio = StdioHandles::from_parts(input_reader, output_writer);
+
+        let bound = bind_mappings(vec![mapping]).await?;
+        let result = run_connection_with_stdio(conn, Hello::V0, bound, Some(stdio)).await;
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        let _ = server_task.await;
+        let err = result.err().context("stream was not rejected")?;
+        assert!(err.to_string().contains(remote), "{err:#}");
+        err.downcast_ref::<Rejection>()
+            .copied()
+            .with_context(|| format!("no rejection in {err:#}"))
+    }
+
+    #[tokio::test]
+    async fn stdio_mapping_errors_when_remote_port_is_refused() -> Result<()> {
+        assert_eq!(stdio_rejection(&[], "9999").await?, Rejection::NotAllowed);
+        assert_eq!(stdio_rejection(&[], "ssh").await?, Rejection::NotAllowed);
+
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        assert_eq!(
+            stdio_rejection(&[closed.to_string().parse()?], &closed.to_string()).await?,
+            Rejection::ConnectionRefused
+        );
+        assert_eq!(
+            stdio_rejection(
+                &["nonexistent.invalid:22".parse()?],
+                "nonexistent.invalid:22"
+            )
+            .await?,
+            Rejection::HostUnreachable
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_opened_too_fast_are_rate_limited() -> Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let (server_endpoint, server_task) =
+            spawn_remote_server(Policy::open(&[port.to_string().parse()?])).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        // Nothing listens on the port, so every allowed stream is refused right away.
+        let mut limited = false;
+        for _ in 0..1000 {
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(&port.to_be_bytes()).await?;
+            let mut buf = [0u8; 1];
+            let Err(ReadError::Reset(code)) = recv.read(&mut buf).await else {
+                panic!("stream was not reset");
+            };
+            if Rejection::from_code(code) == Rejection::RateLimited {
+                limited = true;
+                break;
+            }
+        }
+        assert!(limited, "no stream was rate limited");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_listener_streams_are_reset_locally() -> Result<()> {
+        let (server_endpoint, server_task) = spawn_remote_server(Policy::open(&[])).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let bound = bind_mappings(vec!["0:9999".parse()?]).await?;
+        let port = local_port(&bound[0])?;
+        let client_task = tokio::spawn(run_connection(conn, Hello::V0, bound));
+
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut buf = [0u8; 1];
+        let err = timeout(Duration::from_secs(5), tcp.read(&mut buf))
+            .await?
+            .expect_err("stream should be reset");
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        client_task.abort();
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stdio_mapping_can_run_alongside_listener_mappings() -> Result<()> {
+        let (stdio_remote_port, stdio_echo_task) = spawn_tcp_echo_server().await?;
+        let (listener_remote_port, listener_echo_task) = spawn_tcp_echo_server().await?;
+        let policy = Policy::open(&[
+            format!("{stdio_remote_port}/tcp").parse::<PortSpec>()?,
+            format!("{listener_remote_port}/tcp").parse::<PortSpec>()?,
+        ]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
+
+        let client_key = SecretKey::generate(&mut rand::rng());
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(client_key)
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let stdio_mapping: Mapping = format!("-:{stdio_remote_port}").parse()?;
+        let listener_mapping: Mapping = format!("0:{listener_remote_port}").parse()?;
+        let bound = bind_mappings(vec![stdio_mapping, listener_mapping]).await?;
+        let local_listener_port = local_port(&bound[1])?;
+        let (mut input_writer, input_reader) = duplex(64);
+        let (output_writer, mut output_reader) = duplex(64);
+        let stdio = StdioHandles::from_parts(input_reader, output_writer);
+
+        let client_task = tokio::spawn(async move {
+            run_connection_with_stdio(conn, Hello::V0, bound, Some(stdio)).await
+        });
+
+        input_writer.write_all(b"stdio-live").await?;
+        sleep(Duration::from_millis(100)).await;
 
         let mut listener_client = TcpStream::connect(("127.0.0.1", local_listener_port)).await?;
         listener_client.write_all(b"listener-live").await?;
@@ -620,4 +1618,533 @@ mod tests {
         let _ = listener_echo_task.await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn listener_binds_the_given_address() -> Result<()> {
+        let (remote_port, echo_task) = spawn_tcp_echo_server().await?;
+        let policy = Policy::open(&[format!("{remote_port}/tcp").parse::<PortSpec>()?]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+        let mapping: Mapping = format!("[::1]:0:{remote_port}").parse()?;
+        let bound = bind_mappings(vec![mapping]).await?;
+        assert!(bound[0].local_addr()?.unwrap().starts_with("[::1]:"));
+        let local_port = local_port(&bound[0])?;
+        let client_task = tokio::spawn(async move { run_connection(conn, Hello::V0, bound).await });
+        sleep(Duration::from_millis(100)).await;
+
+        let mut tcp = TcpStream::connect(("::1", local_port)).await?;
+        tcp.write_all(b"over-ipv6").await?;
+        let mut reply = [0u8; 32];
+        let len = tcp.read(&mut reply).await?;
+        assert_eq!(&reply[..len], b"over-ipv6");
+        assert!(TcpStream::connect(("127.0.0.1", local_port)).await.is_err());
+
+        client_task.abort();
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_are_created_restricted() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "punch-bind-{}",
+            SecretKey::generate(&mut rand::rng()).public().fmt_short()
+        ));
+        std::fs::create_dir(&dir)?;
+        let path = dir.join("in.sock");
+        let (listener, socket_file) = bind_unix(&path).await?;
+
+        // Only the socket is left behind, never the directory it was bound in.
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let entries: Vec<_> = std::fs::read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(entries, ["in.sock"]);
+        drop((listener, socket_file));
+        std::fs::remove_dir(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_listener_replaces_stale_socket_and_removes_it() -> Result<()> {
+        let (remote_port, echo_task) = spawn_tcp_echo_server().await?;
+        let policy = Policy::open(&[format!("{remote_port}/tcp").parse::<PortSpec>()?]);
+        let (server_endpoint, server_task) = spawn_remote_server(policy).await?;
+
+        let path = std::env::temp_dir().join(format!(
+            "punch-in-{}.sock",
+            SecretKey::generate(&mut rand::rng()).public()
+        ));
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        assert!(path.exists());
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+        let mapping: Mapping = format!("unix:{}:{remote_port}", path.display()).parse()?;
+        let bound = bind_mappings(vec![mapping]).await?;
+        let client_task = tokio::spawn(async move { run_connection(conn, Hello::V0, bound).await });
+        sleep(Duration::from_millis(100)).await;
+
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mut unix = UnixStream::connect(&path).await?;
+        unix.write_all(b"via-socket").await?;
+        let mut reply = [0u8; 32];
+        let len = unix.read(&mut reply).await?;
+        assert_eq!(&reply[..len], b"via-socket");
+
+        client_task.abort();
+        let _ = client_task.await;
+        assert!(!path.exists());
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unauthorized_peer_is_rejected() -> Result<()> {
+        let server_key = SecretKey::generate(&mut rand::rng());
+        let server_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(server_key)
+            .alpns(vec![ALPN_V0.to_vec()])
+            .bind()
+            .await?;
+
+        let server_task = {
+            let server_endpoint = server_endpoint.clone();
+            tokio::spawn(async move {
+                let incoming = server_endpoint.accept().await.unwrap();
+                let policy = Policy::new(Vec::<PortSpec>::new(), AuthorizedPeers::default());
+                let state = server::ServerState::new(policy, Invites::default());
+                server::handle_connection(incoming, &state).await
+            })
+        };
+
+        let client_key = SecretKey::generate(&mut rand::rng());
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(client_key)
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+
+        let bound = bind_mappings(vec!["0:22".parse()?]).await?;
+        let result = timeout(
+            Duration::from_secs(5),
+            run_connection(conn, Hello::V0, bound),
+        )
+        .await?;
+        let err = result.expect_err("unauthorized peer should be disconnected");
+        assert!(err.to_string().contains("unauthorized"));
+        assert!(server_task.await?.is_err());
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invitation_pairs_peer_once() -> Result<()> {
+        let (echo_port, echo_task) = spawn_tcp_echo_server().await?;
+        let port: PortSpec = echo_port.to_string().parse()?;
+        let (invites, codes) = Invites::mint(1, Duration::from_secs(60))?;
+        let state = Arc::new(server::ServerState::new(
+            Policy::new(vec![port], AuthorizedPeers::default()),
+            invites,
+        ));
+
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state).await?;
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        pair(&client_endpoint, server_endpoint.addr(), &codes[0]).await?;
+        let err = pair(&client_endpoint, server_endpoint.addr(), &codes[0])
+            .await
+            .expect_err("invitation should be single use");
+        assert!(err.to_string().contains("rejected"));
+
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), ALPN_V0)
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&echo_port.to_be_bytes()).await?;
+        send.write_all(b"paired").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"paired");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_pairing_connections_are_closed() -> Result<()> {
+        let (invites, _codes) = Invites::mint(1, Duration::from_secs(60))?;
+        let state = Arc::new(server::ServerState::new(
+            Policy::new(Vec::<PortSpec>::new(), AuthorizedPeers::default()),
+            invites,
+        ));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state).await?;
+
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let conn = client_endpoint
+            .connect(server_endpoint.addr(), PAIR_ALPN)
+            .await?;
+        timeout(Duration::from_secs(30), conn.closed())
+            .await
+            .context("server should give up on a peer that never presents a code")?;
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grant_admits_unlisted_peer_to_granted_ports() -> Result<()> {
+        let (granted_port, granted_task) = spawn_tcp_echo_server().await?;
+        let (other_port, other_task) = spawn_tcp_echo_server().await?;
+        let exposed: Vec<PortSpec> = vec![
+            granted_port.to_string().parse()?,
+            other_port.to_string().parse()?,
+        ];
+
+        let server_key = SecretKey::generate(&mut rand::rng());
+        let policy = Policy::new(exposed.clone(), AuthorizedPeers::default())
+            .with_grant_issuer(server_key.public());
+        let state = Arc::new(server::ServerState::new(policy, Invites::default()));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(server_key.clone(), state).await?;
+
+        let client_key = SecretKey::generate(&mut rand::rng());
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(client_key.clone())
+            .bind()
+            .await?;
+
+        let stranger = SecretKey::generate(&mut rand::rng()).public();
+        let wrong_peer = Grant::sign(&server_key, stranger, exposed.clone(), u64::MAX)?;
+        let Err(err) = connect(&client_endpoint, server_endpoint.addr(), Some(&wrong_peer)).await
+        else {
+            panic!("grant for another peer should be rejected");
+        };
+        assert!(err.to_string().contains("rejected"));
+
+        let token = Grant::sign(
+            &server_key,
+            client_key.public(),
+            vec![exposed[0].clone()],
+            u64::MAX,
+        )?;
+        let session = connect(&client_endpoint, server_endpoint.addr(), Some(&token)).await?;
+        assert_eq!(session.peer(), Hello::LOCAL);
+        let conn = session.conn;
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&granted_port.to_be_bytes()).await?;
+        send.write_all(b"granted").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"granted");
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&other_port.to_be_bytes()).await?;
+        send.finish()?;
+        assert!(recv.read_to_end(4096).await.is_err());
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        granted_task.abort();
+        other_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn control_stream_is_used_when_both_peers_support_it() -> Result<()> {
+        let (port, echo_task) = spawn_tcp_echo_server().await?;
+        let state = Arc::new(server::ServerState::new(
+            Policy::open(&[port.to_string().parse()?]),
+            Invites::default(),
+        ));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state).await?;
+        let (old_endpoint, old_task) =
+            spawn_remote_server(Policy::open(&[port.to_string().parse()?])).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+
+        let session = connect(&client_endpoint, server_endpoint.addr(), None).await?;
+        assert!(session.control.is_some());
+        assert_eq!(session.peer(), Hello::LOCAL);
+        let (mut send, mut recv) = session.conn.open_bi().await?;
+        send.write_all(&port.to_be_bytes()).await?;
+        send.write_all(b"v1").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"v1");
+
+        let session = connect(&client_endpoint, old_endpoint.addr(), None).await?;
+        assert!(session.control.is_none());
+        assert_eq!(session.peer(), Hello::V0);
+        // Old peers cannot read the header of services or other hosts.
+        for mapping in ["2222:ssh", "5432:db.lan:5432"] {
+            let err = session
+                .peer()
+                .check_mappings(&[mapping.parse()?])
+                .unwrap_err();
+            assert!(err.to_string().contains("does not support"), "{err}");
+        }
+        let (mut send, mut recv) = session.conn.open_bi().await?;
+        send.write_all(&port.to_be_bytes()).await?;
+        send.write_all(b"v0").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"v0");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        old_endpoint.close().await;
+        server_task.abort();
+        old_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn offers_follow_the_current_policy() -> Result<()> {
+        let state = Arc::new(server::ServerState::new(
+            Policy::new(
+                ["ssh=22", "8080", "53/udp"]
+                    .iter()
+                    .map(|exposure| exposure.parse::<Exposure>())
+                    .collect::<Result<Vec<_>>>()?,
+                AuthorizedPeers::any(),
+            ),
+            Invites::default(),
+        ));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state.clone()).await?;
+        let (old_endpoint, old_task) = spawn_remote_server(Policy::open(&[])).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+
+        let mut session = connect(&client_endpoint, server_endpoint.addr(), None).await?;
+        let offer = session.offer().await?.context("no offer")?;
+        assert_eq!(offer.to_string(), "22/tcp\n8080/tcp\n53/udp\nssh\n");
+
+        state.reload(Policy::open(&["9000".parse()?]));
+        let offer = session.offer().await?.context("no offer")?;
+        assert_eq!(offer.to_string(), "9000/tcp\n");
+        let err = offer.check_mappings(&["8080:8080".parse()?]).unwrap_err();
+        assert!(err.to_string().contains("does not offer 8080/tcp"), "{err}");
+
+        let mut session = connect(&client_endpoint, old_endpoint.addr(), None).await?;
+        assert!(session.offer().await?.is_none());
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        old_endpoint.close().await;
+        server_task.abort();
+        old_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reverse_ports_forward_back_to_the_client() -> Result<()> {
+        let (local, echo_task) = spawn_tcp_echo_server().await?;
+        let remote = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let policy = Policy::open(&[]).with_bind_ports(vec![PortRange::single(remote)]);
+        let state = Arc::new(server::ServerState::new(policy.clone(), Invites::default()));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state).await?;
+        let (old_endpoint, old_task) = spawn_remote_server(policy).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+
+        let mut session = connect(&client_endpoint, old_endpoint.addr(), None).await?;
+        let err = session
+            .bind_reverse(&[Reverse { remote, local }])
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "remote peer is too old for --reverse");
+
+        let mut session = connect(&client_endpoint, server_endpoint.addr(), None).await?;
+
+        let err = session
+            .bind_reverse(&[Reverse { remote: 1, local }])
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("listening on port 1 is not allowed"),
+            "{err:#}"
+        );
+
+        let reverse = vec![Reverse { remote, local }];
+        session.bind_reverse(&reverse).await?;
+        let reverse_task = tokio::spawn(run_reverse(session.conn.clone(), reverse));
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, remote)).await?;
+        stream.write_all(b"reverse").await?;
+        let mut echoed = [0u8; 7];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut echoed)).await??;
+        assert_eq!(&echoed, b"reverse");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        old_endpoint.close().await;
+        reverse_task.abort();
+        server_task.abort();
+        old_task.abort();
+        echo_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mirror_follows_changes_to_the_offer() -> Result<()> {
+        let (old_port, old_task) = spawn_tcp_echo_server().await?;
+        let (new_port, new_task) = spawn_tcp_echo_server().await?;
+        let local = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+
+        let state = Arc::new(server::ServerState::new(
+            Policy::open(&[old_port.to_string().parse()?]),
+            Invites::default(),
+        ));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state.clone()).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let mut session = connect(&client_endpoint, server_endpoint.addr(), None).await?;
+        let control = session.control.take().context("no control stream")?;
+        let path = std::env::temp_dir().join(format!(
+            "punch-mirror-{}",
+            SecretKey::generate(&mut rand::rng()).public()
+        ));
+        let mirror = Mirror::new(LocalPorts::Range(PortRange::single(local)));
+        let mirror_task = tokio::spawn(run_mirror(
+            session.conn.clone(),
+            session.peer(),
+            control,
+            mirror,
+            Some(path.clone()),
+        ));
+
+        let wait_for_report = async |expected: String| -> Result<()> {
+            timeout(Duration::from_secs(5), async {
+                while std::fs::read_to_string(&path).ok().as_ref() != Some(&expected) {
+                    sleep(Duration::from_millis(20)).await;
+                }
+            })
+            .await
+            .context("report was not written")
+        };
+        let echo = async |message: &[u8]| -> Result<Vec<u8>> {
+            let mut tcp = TcpStream::connect(("127.0.0.1", local)).await?;
+            tcp.write_all(message).await?;
+            let mut buf = vec![0u8; message.len()];
+            tcp.read_exact(&mut buf).await?;
+            Ok(buf)
+        };
+
+        wait_for_report(format!("127.0.0.1:{local}\t{old_port}/tcp\n")).await?;
+        assert_eq!(echo(b"old").await?, b"old");
+
+        state.reload(Policy::open(&[new_port.to_string().parse()?]));
+        wait_for_report(format!("127.0.0.1:{local}\t{new_port}/tcp\n")).await?;
+        assert_eq!(echo(b"new").await?, b"new");
+
+        mirror_task.abort();
+        std::fs::remove_file(&path)?;
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        old_task.abort();
+        new_task.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_applies_to_new_streams_only() -> Result<()> {
+        let (old_port, old_task) = spawn_tcp_echo_server().await?;
+        let (new_port, new_task) = spawn_tcp_echo_server().await?;
+        let old: PortSpec = old_port.to_string().parse()?;
+        let new: PortSpec = new_port.to_string().parse()?;
+
+        let state = Arc::new(server::ServerState::new(
+            Policy::open(&[old]),
+            Invites::default(),
+        ));
+        let (server_endpoint, server_task) =
+            spawn_stateful_server(SecretKey::generate(&mut rand::rng()), state.clone()).await?;
+        let client_endpoint = Endpoint::builder(presets::N0)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+            .bind()
+            .await?;
+        let session = connect(&client_endpoint, server_endpoint.addr(), None).await?;
+        let conn = session.conn.clone();
+
+        let (mut live_send, mut live_recv) = conn.open_bi().await?;
+        live_send.write_all(&old_port.to_be_bytes()).await?;
+        live_send.write_all(b"before").await?;
+        let mut buf = [0u8; 6];
+        live_recv.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"before");
+
+        state.reload(Policy::open(&[new]));
+
+        live_send.write_all(b"after!").await?;
+        live_recv.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"after!");
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&old_port.to_be_bytes()).await?;
+        send.finish()?;
+        assert!(recv.read_to_end(4096).await.is_err());
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&new_port.to_be_bytes()).await?;
+        send.write_all(b"new").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4096).await?, b"new");
+
+        client_endpoint.close().await;
+        server_endpoint.close().await;
+        server_task.abort();
+        old_task.abort();
+        new_task.abort();
+        Ok(())
+    }
 }
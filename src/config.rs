@@ -0,0 +1,324 @@
+use crate::key::Identity;
+use crate::parse::{self, Exposure, Mapping};
+use crate::paths;
+use anyhow::{Context, Result, anyhow};
+use iroh::EndpointId;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+const CONFIG_FILE: &str = "punch.toml";
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(paths::config_dir()?.join(CONFIG_FILE))
+}
+
+/// Settings from `punch.toml`. Command line arguments take precedence.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub identity: Option<Identity>,
+    pub out: OutConfig,
+    /// `punch in` targets by name, from `[in.<name>]` tables.
+    pub peers: BTreeMap<String, PeerConfig>,
+}
+
+/// Defaults for `punch out`.
+#[derive(Debug, Default)]
+pub struct OutConfig {
+    pub ports: Vec<Exposure>,
+    pub allow: Vec<EndpointId>,
+    pub authorized_keys: Option<PathBuf>,
+}
+
+/// A named `punch in` target.
+#[derive(Debug)]
+pub struct PeerConfig {
+    /// An endpoint ID or the name of a known peer.
+    pub peer: String,
+    pub mappings: Vec<Mapping>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    identity: Option<Spanned<String>>,
+    key_file: Option<PathBuf>,
+    #[serde(default)]
+    out: RawOut,
+    #[serde(default, rename = "in")]
+    peers: BTreeMap<String, RawPeer>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOut {
+    #[serde(default)]
+    ports: Vec<Spanned<String>>,
+    #[serde(default)]
+    allow: Vec<Spanned<String>>,
+    authorized_keys: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPeer {
+    peer: Spanned<String>,
+    #[serde(default)]
+    mappings: Vec<Spanned<RawMapping>>,
+}
+
+/// A mapping written as `"3000:8080/tcp"` or as a table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMapping {
+    Short(String),
+    Table(MappingTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingTable {
+    local: RawTarget,
+    remote: RawTarget,
+    protocol: Option<String>,
+}
+
+/// A port number, or a name: `"-"` for stdio locally, a service name remotely.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTarget {
+    Port(u32),
+    Name(String),
+}
+
+impl fmt::Display for RawTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawTarget::Port(port) => write!(f, "{port}"),
+            RawTarget::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl fmt::Display for RawMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawMapping::Short(mapping) => f.write_str(mapping),
+            RawMapping::Table(table) => {
+                write!(f, "{}:{}", table.local, table.remote)?;
+                match &table.protocol {
+                    Some(protocol) => write!(f, "/{protocol}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config at `path`, which must exist, or the default `punch.toml` if it
+    /// exists.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Ok(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        Self::parse(&path, &contents)
+    }
+
+    fn parse(path: &Path, contents: &str) -> Result<Self> {
+        let located = |span: Option<Range<usize>>, message: &dyn fmt::Display| match span {
+            Some(span) => {
+                let (line, column) = line_column(contents, span.start);
+                anyhow!("{}:{line}:{column}: {message}", path.display())
+            }
+            None => anyhow!("{}: {message}", path.display()),
+        };
+        let at = |span: Range<usize>, e: anyhow::Error| located(Some(span), &format!("{e:#}"));
+
+        let raw: RawConfig =
+            toml::from_str(contents).map_err(|e| located(e.span(), &e.message().trim_end()))?;
+        // Relative paths are relative to the directory holding the config file.
+        let relative = |file: PathBuf| match path.parent() {
+            Some(dir) if file.is_relative() => dir.join(file),
+            _ => file,
+        };
+
+        let identity = match (raw.key_file, raw.identity) {
+            (Some(file), _) => Some(Identity::File(relative(file))),
+            (None, Some(name)) => {
+                Some(Identity::named(name.get_ref()).map_err(|e| at(name.span(), e))?)
+            }
+            (None, None) => None,
+        };
+
+        let mut out = OutConfig {
+            authorized_keys: raw.out.authorized_keys.map(relative),
+            ..OutConfig::default()
+        };
+        for port in raw.out.ports {
+            let exposure: Exposure = port.get_ref().parse().map_err(|e| at(port.span(), e))?;
+            parse::check_exposure(&out.ports, &exposure).map_err(|e| at(port.span(), e))?;
+            out.ports.push(exposure);
+        }
+        for peer in raw.out.allow {
+            let id = peer.get_ref().parse().map_err(|e: iroh::KeyParsingError| {
+                at(peer.span(), anyhow!("invalid endpoint ID: {e}"))
+            })?;
+            out.allow.push(id);
+        }
+
+        let mut peers = BTreeMap::new();
+        for (name, raw) in raw.peers {
+            let mut mappings = Vec::with_capacity(raw.mappings.len());
+            for mapping in raw.mappings {
+                let parsed = parse::parse_mapping(&mapping.get_ref().to_string())
+                    .map_err(|e| at(mapping.span(), e))?;
+                for parsed in parsed {
+                    parse::check_mapping(&mappings, &parsed).map_err(|e| at(mapping.span(), e))?;
+                    mappings.push(parsed);
+                }
+            }
+            peers.insert(
+                name,
+                PeerConfig {
+                    peer: raw.peer.into_inner(),
+                    mappings,
+                },
+            );
+        }
+
+        Ok(Self {
+            identity,
+            out,
+            peers,
+        })
+    }
+}
+
+/// Converts a byte offset into a 1-based line and column.
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{LocalTarget, Protocol, Remote};
+    use iroh::SecretKey;
+
+    fn parse(contents: &str) -> Result<Config> {
+        Config::parse(Path::new("/etc/punch/punch.toml"), contents)
+    }
+
+    fn error(contents: &str) -> String {
+        parse(contents).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_out_in_and_identity() {
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        let contents = format!(
+            r#"
+identity = "web"
+
+[out]
+ports = ["ssh=22", "53/udp"]
+allow = ["{peer}"]
+authorized_keys = "keys"
+
+[in.prod-db]
+peer = "{peer}"
+mappings = ["5432:5432", {{ local = "-", remote = "ssh" }}, {{ local = 5300, remote = 53, protocol = "udp" }}]
+"#
+        );
+        let config = parse(&contents).unwrap();
+
+        assert_eq!(config.identity, Some(Identity::Named("web".into())));
+        assert_eq!(
+            config.out.ports,
+            vec!["ssh=22".parse().unwrap(), "53/udp".parse().unwrap()]
+        );
+        assert_eq!(config.out.allow, vec![peer]);
+        assert_eq!(
+            config.out.authorized_keys,
+            Some(PathBuf::from("/etc/punch/keys"))
+        );
+
+        let prod = &config.peers["prod-db"];
+        assert_eq!(prod.peer, peer.to_string());
+        assert_eq!(prod.mappings.len(), 3);
+        assert_eq!(prod.mappings[1].local, LocalTarget::Stdio);
+        assert_eq!(prod.mappings[1].remote, Remote::Service("ssh".into()));
+        assert_eq!(prod.mappings[2].local, LocalTarget::loopback(5300));
+        assert_eq!(prod.mappings[2].protocol, Protocol::Udp);
+    }
+
+    #[test]
+    fn key_file_overrides_identity() {
+        let config = parse("identity = \"web\"\nkey_file = \"/keys/web.key\"\n").unwrap();
+        assert_eq!(
+            config.identity,
+            Some(Identity::File(PathBuf::from("/keys/web.key")))
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_value() {
+        let err = error("[out]\nports = [\"22\", \"0\"]\n");
+        assert!(err.starts_with("/etc/punch/punch.toml:2:16: "), "{err}");
+        assert!(err.contains("port must be 1–65535"), "{err}");
+
+        let err = error("[out]\nports = [\"22\", \"22/tcp\"]\n");
+        assert!(
+            err.starts_with("/etc/punch/punch.toml:2:16: duplicate port"),
+            "{err}"
+        );
+
+        let err = error("[out]\nports = [\"ssh=22\", \"ssh=2222\"]\n");
+        assert!(
+            err.starts_with("/etc/punch/punch.toml:2:20: duplicate service: ssh"),
+            "{err}"
+        );
+
+        let err = error("[in.a]\npeer = \"x\"\nmappings = [\"-:22\", \"-:23\"]\n");
+        assert!(
+            err.starts_with("/etc/punch/punch.toml:3:21: at most one stdio"),
+            "{err}"
+        );
+
+        let err = error(
+            "[in.a]\npeer = \"x\"\nmappings = [{ local = \"-\", remote = 53, protocol = \"udp\" }]\n",
+        );
+        assert!(err.contains(":3:13: "), "{err}");
+        assert!(err.contains("stdio mappings must use tcp"), "{err}");
+    }
+
+    #[test]
+    fn syntax_and_unknown_keys_are_located() {
+        let err = error("[out]\nports = [22\n");
+        assert!(err.starts_with("/etc/punch/punch.toml:2:"), "{err}");
+
+        let err = error("[out]\nport = [\"22\"]\n");
+        assert!(err.starts_with("/etc/punch/punch.toml:2:1: "), "{err}");
+        assert!(err.contains("port"), "{err}");
+    }
+
+    #[test]
+    fn explicit_config_must_exist() {
+        let path = std::env::temp_dir().join("punch-missing-config.toml");
+        assert!(Config::load(Some(&path)).is_err());
+    }
+}
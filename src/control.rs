@@ -0,0 +1,536 @@
+use crate::dest::DestRule;
+use crate::parse::{Mapping, PortRange, PortSpec, Protocol, Remote, Target};
+use crate::policy::{AllowedPorts, TargetSet};
+use crate::udp;
+use anyhow::{Context, Result, bail, ensure};
+use iroh::endpoint::{Connection, ReadExactError, RecvStream, SendStream};
+use std::fmt;
+use tokio::sync::watch;
+
+/// ALPN of the original protocol. Streams start with the 2-byte port header right away.
+pub const ALPN_V0: &[u8] = b"punch/0";
+/// ALPN of connections whose first stream is the control stream.
+pub const ALPN_V1: &[u8] = b"punch/1";
+
+pub const VERSION: u16 = 1;
+
+/// The peer forwards UDP datagrams.
+pub const FEATURE_UDP: u32 = 1 << 0;
+/// The peer answers offer requests on the control stream.
+pub const FEATURE_OFFER: u32 = 1 << 1;
+/// The peer sends its offer again each time it changes, once asked to.
+pub const FEATURE_WATCH: u32 = 1 << 2;
+/// The peer listens on ports for `--reverse` when asked to.
+pub const FEATURE_REVERSE: u32 = 1 << 3;
+
+/// Target kinds a peer can reach: `22`, `ssh` and `db.lan:5432`.
+pub const TARGET_PORT: u8 = 1 << 0;
+pub const TARGET_SERVICE: u8 = 1 << 1;
+pub const TARGET_HOST: u8 = 1 << 2;
+
+/// Length of the fields this version reads. Later versions may append more.
+const HELLO_LEN: usize = 9;
+/// Longest hello accepted, so a peer cannot make us buffer an arbitrary amount.
+const MAX_HELLO_LEN: usize = 1024;
+/// Longest offer accepted.
+const MAX_OFFER_LEN: usize = 1 << 20;
+
+/// Requests a client sends on the control stream after the hellos.
+const REQUEST_OFFER: u8 = 1;
+/// Asks for the offer now and after every change. No more requests follow it.
+const REQUEST_WATCH: u8 = 2;
+/// Asks the server to listen on a port, followed by the port (u16 BE). The reply is a
+/// status byte, 0 on success, or 1 followed by a u16-length error message.
+const REQUEST_BIND: u8 = 3;
+
+/// What each side of a `punch/1` connection supports, exchanged on the control stream.
+///
+/// Encoded as `len (u16 BE) | version (u16 BE) | features (u32 BE) |
+/// max datagram size (u16 BE) | target kinds (u8)`, where `len` counts the bytes after
+/// it. Bytes past the known fields are ignored so newer peers can add fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub features: u32,
+    /// Largest UDP payload the peer accepts in a datagram.
+    pub max_datagram_size: u16,
+    pub target_kinds: u8,
+}
+
+impl Hello {
+    /// What this build of punch supports.
+    pub const LOCAL: Hello = Hello {
+        version: VERSION,
+        features: FEATURE_UDP | FEATURE_OFFER | FEATURE_WATCH | FEATURE_REVERSE,
+        max_datagram_size: udp::MAX_UDP_PACKET_SIZE as u16,
+        target_kinds: TARGET_PORT | TARGET_SERVICE | TARGET_HOST,
+    };
+
+    /// What is assumed of a `punch/0` peer, which cannot say. Its streams only carry the
+    /// 2-byte port header, so it reaches neither named services nor other hosts.
+    pub const V0: Hello = Hello {
+        version: 0,
+        features: FEATURE_UDP,
+        max_datagram_size: udp::MAX_UDP_PACKET_SIZE as u16,
+        target_kinds: TARGET_PORT,
+    };
+
+    /// What both sides support.
+    pub fn negotiate(&self, peer: &Hello) -> Hello {
+        Hello {
+            version: self.version.min(peer.version),
+            features: self.features & peer.features,
+            max_datagram_size: self.max_datagram_size.min(peer.max_datagram_size),
+            target_kinds: self.target_kinds & peer.target_kinds,
+        }
+    }
+
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut hello = Vec::with_capacity(2 + HELLO_LEN);
+        hello.extend_from_slice(&(HELLO_LEN as u16).to_be_bytes());
+        hello.extend_from_slice(&self.version.to_be_bytes());
+        hello.extend_from_slice(&self.features.to_be_bytes());
+        hello.extend_from_slice(&self.max_datagram_size.to_be_bytes());
+        hello.push(self.target_kinds);
+        hello
+    }
+
+    /// Decodes the fields after the length prefix.
+    pub fn decode(body: &[u8]) -> Result<Self> {
+        ensure!(body.len() >= HELLO_LEN, "control hello is truncated");
+        let hello = Hello {
+            version: u16::from_be_bytes([body[0], body[1]]),
+            features: u32::from_be_bytes([body[2], body[3], body[4], body[5]]),
+            max_datagram_size: u16::from_be_bytes([body[6], body[7]]),
+            target_kinds: body[8],
+        };
+        ensure!(
+            hello.version >= 1,
+            "control hello has version {}",
+            hello.version
+        );
+        Ok(hello)
+    }
+
+    /// Rejects mappings that need something the peer does not support, before any
+    /// listener is opened.
+    pub fn check_mappings(&self, mappings: &[Mapping]) -> Result<()> {
+        for mapping in mappings {
+            if mapping.protocol == Protocol::Udp && !self.supports(FEATURE_UDP) {
+                bail!("remote peer does not forward udp");
+            }
+            let (kind, name) = match &mapping.remote {
+                Remote::Service(_) => (TARGET_SERVICE, "named services"),
+                Remote::Target(Target::Inet { host: None, .. }) => (TARGET_PORT, "ports"),
+                Remote::Target(_) => (TARGET_HOST, "targets on other hosts"),
+            };
+            if self.target_kinds & kind == 0 {
+                bail!(
+                    "remote peer does not support {name}, needed by {}",
+                    mapping.remote
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The control stream of a `punch/1` connection, kept open for the whole connection.
+pub struct Control {
+    /// What both sides support.
+    pub negotiated: Hello,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl Control {
+    /// Asks the server what it offers this peer.
+    pub async fn offer(&mut self) -> Result<Offer> {
+        ensure!(
+            self.negotiated.supports(FEATURE_OFFER),
+            "remote peer cannot list what it offers"
+        );
+        self.send.write_all(&[REQUEST_OFFER]).await?;
+        self.next_offer().await
+    }
+
+    /// Asks the server to send its offer now and whenever it changes. Read them with
+    /// [`Control::next_offer`].
+    pub async fn watch(&mut self) -> Result<()> {
+        ensure!(
+            self.negotiated.supports(FEATURE_WATCH),
+            "remote peer cannot report changes to what it offers"
+        );
+        self.send.write_all(&[REQUEST_WATCH]).await?;
+        Ok(())
+    }
+
+    /// Asks the server to listen on `port` and open a stream to this peer for each
+    /// connection it accepts there.
+    pub async fn bind(&mut self, port: u16) -> Result<()> {
+        ensure!(
+            self.negotiated.supports(FEATURE_REVERSE),
+            "remote peer does not support reverse forwarding"
+        );
+        let mut request = vec![REQUEST_BIND];
+        request.extend_from_slice(&port.to_be_bytes());
+        self.send.write_all(&request).await?;
+
+        let mut status = [0u8; 1];
+        self.recv
+            .read_exact(&mut status)
+            .await
+            .context("peer closed the control stream")?;
+        if status[0] == 0 {
+            return Ok(());
+        }
+        let mut len = [0u8; 2];
+        self.recv.read_exact(&mut len).await?;
+        let mut message = vec![0u8; usize::from(u16::from_be_bytes(len))];
+        self.recv.read_exact(&mut message).await?;
+        bail!("{}", String::from_utf8_lossy(&message))
+    }
+
+    pub async fn next_offer(&mut self) -> Result<Offer> {
+        let mut len = [0u8; 4];
+        self.recv
+            .read_exact(&mut len)
+            .await
+            .context("peer closed the control stream")?;
+        let len = u32::from_be_bytes(len) as usize;
+        ensure!(len <= MAX_OFFER_LEN, "offer is too long");
+        let mut body = vec![0u8; len];
+        self.recv.read_exact(&mut body).await?;
+        Offer::decode(&body)
+    }
+
+    /// Answers the client's requests with the offer built from `allowed` at that time,
+    /// and bind requests with `bind`. Returns once the client closes the control stream.
+    pub async fn serve<F>(
+        &mut self,
+        mut allowed: watch::Receiver<AllowedPorts>,
+        bind: impl Fn(u16) -> F,
+    ) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let mut request = [0u8; 1];
+        loop {
+            match self.recv.read_exact(&mut request).await {
+                Ok(()) => {}
+                Err(ReadExactError::FinishedEarly(0)) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            match request[0] {
+                REQUEST_OFFER => {
+                    let offer = allowed.borrow().offer();
+                    self.send_offer(&offer).await?;
+                }
+                REQUEST_WATCH => {
+                    let mut sent = allowed.borrow_and_update().offer();
+                    self.send_offer(&sent).await?;
+                    while allowed.changed().await.is_ok() {
+                        // Reloads that leave this peer's offer unchanged are not reported.
+                        let offer = allowed.borrow_and_update().offer();
+                        if offer != sent {
+                            self.send_offer(&offer).await?;
+                            sent = offer;
+                        }
+                    }
+                    return Ok(());
+                }
+                REQUEST_BIND => {
+                    let mut port = [0u8; 2];
+                    self.recv.read_exact(&mut port).await?;
+                    match bind(u16::from_be_bytes(port)).await {
+                        Ok(()) => self.send.write_all(&[0]).await?,
+                        Err(e) => {
+                            let message = format!("{e:#}");
+                            let message = &message.as_bytes()[..message.len().min(1024)];
+                            let mut reply = vec![1];
+                            reply.extend_from_slice(&(message.len() as u16).to_be_bytes());
+                            reply.extend_from_slice(message);
+                            self.send.write_all(&reply).await?;
+                        }
+                    }
+                }
+                request => bail!("unknown control request {request}"),
+            }
+        }
+    }
+
+    async fn send_offer(&mut self, offer: &Offer) -> Result<()> {
+        let body = offer.encode();
+        self.send
+            .write_all(&(body.len() as u32).to_be_bytes())
+            .await?;
+        self.send.write_all(&body).await?;
+        Ok(())
+    }
+}
+
+/// Opens the control stream and exchanges hellos, sending ours first.
+pub async fn open(conn: &Connection) -> Result<Control> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&Hello::LOCAL.encode()).await?;
+    let peer = read_hello(&mut recv).await?;
+    Ok(Control {
+        negotiated: Hello::LOCAL.negotiate(&peer),
+        send,
+        recv,
+    })
+}
+
+/// Accepts the control stream a `punch/1` client opens first and answers its hello.
+pub async fn accept(conn: &Connection) -> Result<Control> {
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    let peer = read_hello(&mut recv).await?;
+    send.write_all(&Hello::LOCAL.encode()).await?;
+    Ok(Control {
+        negotiated: Hello::LOCAL.negotiate(&peer),
+        send,
+        recv,
+    })
+}
+
+async fn read_hello(recv: &mut RecvStream) -> Result<Hello> {
+    let mut len = [0u8; 2];
+    recv.read_exact(&mut len)
+        .await
+        .context("peer closed the control stream")?;
+    let len = u16::from_be_bytes(len) as usize;
+    ensure!(len <= MAX_HELLO_LEN, "control hello is too long");
+    let mut body = vec![0u8; len];
+    recv.read_exact(&mut body).await?;
+    Hello::decode(&body)
+}
+
+/// What a `punch out` peer lets the connecting peer reach.
+///
+/// Encoded as UTF-8 lines of `port <spec>`, `service <name>`, `dest <rule>` or
+/// `bind <ports>`. Lines of other kinds are skipped so newer peers can add them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Offer {
+    pub ports: Vec<PortSpec>,
+    pub services: Vec<String>,
+    /// Destinations the peer may choose itself.
+    pub dest: Vec<DestRule>,
+    /// Ports the peer may ask to be listened on with `--reverse`.
+    pub bind: Vec<PortRange>,
+}
+
+impl Offer {
+    pub fn encode(&self) -> Vec<u8> {
+        let ports = self.ports.iter().map(|port| format!("port {port}\n"));
+        let services = self.services.iter().map(|name| format!("service {name}\n"));
+        let dest = self.dest.iter().map(|rule| format!("dest {rule}\n"));
+        let bind = self.bind.iter().map(|ports| format!("bind {ports}\n"));
+        ports
+            .chain(services)
+            .chain(dest)
+            .chain(bind)
+            .collect::<String>()
+            .into()
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Self> {
+        let body = std::str::from_utf8(body).context("offer is not valid UTF-8")?;
+        let mut offer = Offer::default();
+        for line in body.lines() {
+            let (kind, value) = line.split_once(' ').unwrap_or((line, ""));
+            let invalid = || format!("invalid offer entry {line:?}");
+            match kind {
+                "port" => offer.ports.push(value.parse().with_context(invalid)?),
+                "service" => offer.services.push(value.to_string()),
+                "dest" => offer.dest.push(value.parse().with_context(invalid)?),
+                "bind" => offer.bind.push(value.parse().with_context(invalid)?),
+                _ => {}
+            }
+        }
+        Ok(offer)
+    }
+
+    /// Rejects mappings whose remote is not offered, so `punch in` fails before any
+    /// listener is opened rather than on every connection.
+    pub fn check_mappings(&self, mappings: &[Mapping]) -> Result<()> {
+        let offered = |protocol| -> TargetSet {
+            self.ports
+                .iter()
+                .filter(|port| port.protocol == protocol)
+                .map(|port| port.target.clone())
+                .collect()
+        };
+        let (tcp, udp) = (offered(Protocol::Tcp), offered(Protocol::Udp));
+        for mapping in mappings {
+            let available = match &mapping.remote {
+                Remote::Service(name) => self.services.contains(name),
+                Remote::Target(target) => {
+                    let ports = match mapping.protocol {
+                        Protocol::Tcp => &tcp,
+                        Protocol::Udp => &udp,
+                    };
+                    ports.contains(target)
+                        || self
+                            .dest
+                            .iter()
+                            .any(|rule| rule.may_permit(target, mapping.protocol))
+                }
+            };
+            if !available {
+                bail!(
+                    "remote peer does not offer {}/{}, see punch ls",
+                    mapping.remote,
+                    mapping.protocol.suffix()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Offer {
+    /// One entry per line, in the form used by mappings: `22/tcp`, `ssh`,
+    /// `dest:10.0.0.0/24:5432/tcp` for destination rules and `bind:9000-9100` for ports
+    /// that may be listened on.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for port in &self.ports {
+            writeln!(f, "{port}")?;
+        }
+        for name in &self.services {
+            writeln!(f, "{name}")?;
+        }
+        for rule in &self.dest {
+            writeln!(f, "dest:{rule}")?;
+        }
+        for ports in &self.bind {
+            writeln!(f, "bind:{ports}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_roundtrip() {
+        let encoded = Hello::LOCAL.encode();
+        assert_eq!(encoded.len(), 2 + HELLO_LEN);
+        assert_eq!(Hello::decode(&encoded[2..]).unwrap(), Hello::LOCAL);
+    }
+
+    #[test]
+    fn newer_hellos_are_read_and_negotiated_down() {
+        let newer = Hello {
+            version: 7,
+            features: FEATURE_UDP | 1 << 20,
+            max_datagram_size: 1200,
+            target_kinds: 0xff,
+        };
+        let mut encoded = newer.encode();
+        encoded.extend_from_slice(b"fields from the future");
+        assert_eq!(Hello::decode(&encoded[2..]).unwrap(), newer);
+
+        let negotiated = Hello::LOCAL.negotiate(&newer);
+        assert_eq!(negotiated.version, VERSION);
+        assert_eq!(negotiated.features, FEATURE_UDP);
+        assert_eq!(negotiated.max_datagram_size, 1200);
+        assert_eq!(negotiated.target_kinds, Hello::LOCAL.target_kinds);
+    }
+
+    #[test]
+    fn invalid_hellos_are_rejected() {
+        assert!(Hello::decode(&Hello::LOCAL.encode()[2..8]).is_err());
+        let mut v0 = Hello::V0.encode();
+        assert!(Hello::decode(&v0[2..]).is_err());
+        v0[3] = 1;
+        assert!(Hello::decode(&v0[2..]).is_ok());
+    }
+
+    #[test]
+    fn mappings_need_what_the_peer_supports() {
+        let mappings: Vec<Mapping> = ["3000:8080", "2222:ssh", "5300:53/udp"]
+            .iter()
+            .map(|mapping| mapping.parse().unwrap())
+            .collect();
+        assert!(Hello::LOCAL.check_mappings(&mappings).is_ok());
+
+        let ports_only = Hello {
+            target_kinds: TARGET_PORT,
+            ..Hello::LOCAL
+        };
+        let err = ports_only.check_mappings(&mappings).unwrap_err();
+        assert!(err.to_string().contains("named services"), "{err}");
+        assert!(
+            ports_only
+                .check_mappings(&["5432:db.lan:5432".parse().unwrap()])
+                .is_err()
+        );
+
+        let err = Hello::V0.check_mappings(&mappings).unwrap_err();
+        assert!(err.to_string().contains("named services"), "{err}");
+        assert!(
+            Hello::V0
+                .check_mappings(&["5432:db.lan:5432".parse().unwrap()])
+                .is_err()
+        );
+        assert!(
+            Hello::V0
+                .check_mappings(&["3000:8080".parse().unwrap(), "5300:53/udp".parse().unwrap()])
+                .is_ok()
+        );
+
+        let no_udp = Hello {
+            features: 0,
+            ..Hello::LOCAL
+        };
+        assert!(no_udp.check_mappings(&mappings[..2]).is_ok());
+        assert!(no_udp.check_mappings(&mappings).is_err());
+    }
+
+    #[test]
+    fn offers_roundtrip_and_skip_unknown_entries() {
+        let offer = Offer {
+            ports: ["22/tcp", "10000-10100/udp", "db.lan:5432/tcp"]
+                .iter()
+                .map(|port| port.parse().unwrap())
+                .collect(),
+            services: vec!["ssh".into()],
+            dest: vec!["10.0.0.0/24:5432".parse().unwrap()],
+            bind: vec!["9000-9100".parse().unwrap()],
+        };
+        let mut encoded = offer.encode();
+        encoded.extend_from_slice(b"quota 10\n");
+        assert_eq!(Offer::decode(&encoded).unwrap(), offer);
+        assert_eq!(
+            offer.to_string(),
+            "22/tcp\n10000-10100/udp\ndb.lan:5432/tcp\nssh\ndest:10.0.0.0/24:5432/tcp\nbind:9000-9100\n"
+        );
+        assert!(Offer::decode(b"port 0\n").is_err());
+    }
+
+    #[test]
+    fn mappings_must_be_offered() {
+        let offer = Offer::decode(
+            b"port 22/tcp\nport 10000-10100/udp\nservice ssh\ndest 10.0.0.0/24:5432/tcp\n",
+        )
+        .unwrap();
+        let check =
+            |mapping: &str| offer.check_mappings(&crate::parse::parse_mapping(mapping).unwrap());
+        assert!(check("2222:22").is_ok());
+        assert!(check("2222:ssh").is_ok());
+        assert!(check("10000-10002:10000-10002/udp").is_ok());
+        assert!(check("5432:10.0.0.7:5432").is_ok());
+
+        let err = check("8080:8080").unwrap_err();
+        assert!(err.to_string().contains("does not offer 8080/tcp"), "{err}");
+        assert!(check("2222:22/udp").is_err());
+        assert!(check("2222:web").is_err());
+        assert!(check("5432:10.0.0.7:5433").is_err());
+    }
+}
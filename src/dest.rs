@@ -0,0 +1,298 @@
+use crate::parse::{self, LOOPBACK, PortRange, Protocol, Target};
+use anyhow::{Context, Result, bail};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::lookup_host;
+
+/// Hosts a destination rule covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// Addresses in a network such as `10.0.0.0/24`. A single address is a full-length prefix.
+    Network { addr: IpAddr, prefix: u8 },
+    /// Exactly this hostname.
+    Name(String),
+    /// Subdomains of this name: `*.internal` matches `db.internal` but not `internal`.
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn matches_name(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Network { .. } => false,
+            HostPattern::Name(name) => host == name,
+            HostPattern::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| !sub.is_empty()),
+        }
+    }
+
+    fn matches_addr(&self, ip: IpAddr) -> bool {
+        let HostPattern::Network { addr, prefix } = *self else {
+            return false;
+        };
+        match (addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((addr, prefix)) = s.split_once('/') {
+            let addr: IpAddr = addr.parse().context("invalid network address")?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix: u8 = prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("network prefix must be 0–{max}"))?;
+            return Ok(HostPattern::Network { addr, prefix });
+        }
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            let prefix = if addr.is_ipv4() { 32 } else { 128 };
+            return Ok(HostPattern::Network { addr, prefix });
+        }
+        if let Some(domain) = s.strip_prefix("*.") {
+            return Ok(HostPattern::Subdomains(format!(
+                ".{}",
+                parse::parse_host(domain)?
+            )));
+        }
+        Ok(HostPattern::Name(parse::parse_host(s)?))
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Network {
+                addr: IpAddr::V4(addr),
+                prefix: 32,
+            } => write!(f, "{addr}"),
+            HostPattern::Network {
+                addr: IpAddr::V6(addr),
+                prefix: 128,
+            } => write!(f, "[{addr}]"),
+            HostPattern::Network {
+                addr: IpAddr::V4(addr),
+                prefix,
+            } => write!(f, "{addr}/{prefix}"),
+            HostPattern::Network {
+                addr: IpAddr::V6(addr),
+                prefix,
+            } => write!(f, "[{addr}/{prefix}]"),
+            HostPattern::Name(name) => f.write_str(name),
+            HostPattern::Subdomains(domain) => write!(f, "*{domain}"),
+        }
+    }
+}
+
+/// A destination `punch in` may choose itself, e.g. `10.0.0.0/24:5432` or
+/// `*.internal:443/tcp`, given to `punch out --allow-dest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestRule {
+    pub host: HostPattern,
+    pub ports: PortRange,
+    pub protocol: Protocol,
+}
+
+impl DestRule {
+    /// Whether any address `target` resolves to could be allowed, checked before
+    /// resolving it.
+    pub fn may_permit(&self, target: &Target, protocol: Protocol) -> bool {
+        let Target::Inet { host, ports } = target else {
+            return false;
+        };
+        let Some(port) = ports.single_port() else {
+            return false;
+        };
+        if self.protocol != protocol || !self.ports.contains(port) {
+            return false;
+        }
+        match &self.host {
+            HostPattern::Network { .. } => true,
+            pattern => pattern.matches_name(host.as_deref().unwrap_or(LOOPBACK)),
+        }
+    }
+}
+
+impl FromStr for DestRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // CIDR prefixes also use '/', so only a known protocol is taken as a suffix.
+        let (rest, protocol) = match s.rsplit_once('/') {
+            Some((rest, suffix)) if matches!(suffix, "tcp" | "udp") => (rest, suffix.parse()?),
+            _ => (s, Protocol::Tcp),
+        };
+        let (host, ports) = if let Some(rest) = rest.strip_prefix('[') {
+            rest.split_once("]:")
+                .context("IPv6 destinations must be [<address>]:<port>")?
+        } else {
+            rest.rsplit_once(':')
+                .context("destination must be <host>:<port>")?
+        };
+        if host.contains(':') && !rest.starts_with('[') {
+            bail!("IPv6 destinations must be [<address>]:<port>");
+        }
+        Ok(Self {
+            host: host.parse()?,
+            ports: ports.parse()?,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for DestRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}/{}", self.host, self.ports, self.protocol.suffix())
+    }
+}
+
+/// Resolves `target` and keeps the addresses `rules` allow. A host matching a hostname
+/// pattern may use every address it resolves to. Any other host only reaches addresses
+/// inside an allowed network, so a name cannot lead outside the networks the rules cover.
+pub async fn resolve(
+    rules: &[DestRule],
+    target: &Target,
+    protocol: Protocol,
+) -> Result<Vec<SocketAddr>> {
+    let rules: Vec<&DestRule> = rules
+        .iter()
+        .filter(|rule| rule.may_permit(target, protocol))
+        .collect();
+    let addr = target.inet_addr().filter(|_| !rules.is_empty());
+    let Some((host, port)) = addr else {
+        bail!("{target} not in expose list");
+    };
+
+    let named = rules.iter().any(|rule| rule.host.matches_name(host));
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .with_context(|| format!("failed to resolve {host}"))?
+        .filter(|addr| named || rules.iter().any(|rule| rule.host.matches_addr(addr.ip())))
+        .collect();
+    if addrs.is_empty() {
+        bail!("{target} not in expose list");
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<DestRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rules_parse_and_display() {
+        let rule: DestRule = "10.0.0.0/24:5432".parse().unwrap();
+        assert_eq!(
+            rule.host,
+            HostPattern::Network {
+                addr: "10.0.0.0".parse().unwrap(),
+                prefix: 24
+            }
+        );
+        assert_eq!(rule.to_string(), "10.0.0.0/24:5432/tcp");
+        assert_eq!(
+            "*.Internal:443".parse::<DestRule>().unwrap().to_string(),
+            "*.internal:443/tcp"
+        );
+        assert_eq!(
+            "[fd00::/64]:5000-5010/udp"
+                .parse::<DestRule>()
+                .unwrap()
+                .to_string(),
+            "[fd00::/64]:5000-5010/udp"
+        );
+        assert_eq!(
+            "10.0.0.7:22".parse::<DestRule>().unwrap().to_string(),
+            "10.0.0.7:22/tcp"
+        );
+
+        assert!("10.0.0.0/33:5432".parse::<DestRule>().is_err());
+        assert!("10.0.0.0/24".parse::<DestRule>().is_err());
+        assert!("fd00::/64:5432".parse::<DestRule>().is_err());
+        assert!("*.internal:0".parse::<DestRule>().is_err());
+        assert!("*:443".parse::<DestRule>().is_err());
+    }
+
+    #[test]
+    fn patterns_match_hosts_and_networks() {
+        let network: HostPattern = "10.0.0.0/24".parse().unwrap();
+        assert!(network.matches_addr("10.0.0.7".parse().unwrap()));
+        assert!(!network.matches_addr("10.0.1.7".parse().unwrap()));
+        assert!(!network.matches_addr("::1".parse().unwrap()));
+        let everything: HostPattern = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.matches_addr("192.0.2.1".parse().unwrap()));
+        let v6: HostPattern = "fd00::/64".parse().unwrap();
+        assert!(v6.matches_addr("fd00::1234".parse().unwrap()));
+        assert!(!v6.matches_addr("fd01::1".parse().unwrap()));
+
+        let internal: HostPattern = "*.internal".parse().unwrap();
+        assert!(internal.matches_name("db.internal"));
+        assert!(internal.matches_name("a.b.internal"));
+        assert!(!internal.matches_name("internal"));
+        assert!(!internal.matches_name("notinternal"));
+        assert!(!internal.matches_name("db.internal.example"));
+    }
+
+    #[test]
+    fn rules_are_checked_before_resolving() {
+        let rules = rules(&["10.0.0.0/24:5432", "*.internal:443", "*.internal:53/udp"]);
+        let permits = |target: &str, protocol| {
+            let target: Target = target.parse().unwrap();
+            rules.iter().any(|rule| rule.may_permit(&target, protocol))
+        };
+        assert!(permits("10.0.0.7:5432", Protocol::Tcp));
+        assert!(permits("db.lan:5432", Protocol::Tcp));
+        assert!(!permits("10.0.0.7:5433", Protocol::Tcp));
+        assert!(permits("web.internal:443", Protocol::Tcp));
+        assert!(!permits("web.internal:443", Protocol::Udp));
+        assert!(permits("dns.internal:53", Protocol::Udp));
+        assert!(!permits("web.example:443", Protocol::Tcp));
+    }
+
+    #[tokio::test]
+    async fn resolved_addresses_must_be_allowed() {
+        let allowed = rules(&["127.0.0.0/8:8000-8100"]);
+        let addrs = resolve(&allowed, &"127.0.0.9:8080".parse().unwrap(), Protocol::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["127.0.0.9:8080".parse().unwrap()]);
+        let addrs = resolve(&allowed, &"8080".parse().unwrap(), Protocol::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8080".parse().unwrap()]);
+
+        assert!(
+            resolve(&allowed, &"10.0.0.1:8080".parse().unwrap(), Protocol::Tcp)
+                .await
+                .is_err()
+        );
+        assert!(
+            resolve(&allowed, &"127.0.0.1:9000".parse().unwrap(), Protocol::Tcp)
+                .await
+                .is_err()
+        );
+        assert!(
+            resolve(&[], &"127.0.0.1:8080".parse().unwrap(), Protocol::Tcp)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::parse::PortSpec;
+use anyhow::{Context, Result, bail, ensure};
+use iroh::endpoint::{RecvStream, SendStream};
+use iroh::{EndpointId, PublicKey, SecretKey, Signature};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ALPN for `punch/0` connections that present a grant before forwarding.
+pub const GRANT_ALPN: &[u8] = b"punch/grant/0";
+/// ALPN for `punch/1` connections that present a grant before opening the control stream.
+pub const GRANT_ALPN_V1: &[u8] = b"punch/grant/1";
+
+const VERSION: u8 = 1;
+/// Signatures cover this context followed by the grant body.
+const SIGNING_CONTEXT: &[u8] = b"punch grant v1";
+/// Longest encoded token accepted, well above any realistic list of ports.
+const MAX_TOKEN_LEN: usize = 8192;
+const ACCEPTED: u8 = 0;
+const REJECTED: u8 = 1;
+
+/// Access to a set of ports, signed offline by the `punch out` identity.
+///
+/// Tokens are lowercase base32 of
+/// `version | issuer (32) | peer (32) | expires (u64 BE, unix secs) | ports len (u16 BE) |
+/// ports | signature (64)`, where `ports` is space separated port specs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grant {
+    pub issuer: EndpointId,
+    pub peer: EndpointId,
+    pub ports: Vec<PortSpec>,
+    /// Seconds since the Unix epoch after which the grant is no longer valid.
+    pub expires: u64,
+}
+
+impl Grant {
+    /// Signs the grant with `issuer`, which must be the key of the `punch out` endpoint.
+    /// Fails if the token would be too long for servers to accept.
+    pub fn sign(
+        issuer: &SecretKey,
+        peer: EndpointId,
+        ports: Vec<PortSpec>,
+        expires: u64,
+    ) -> Result<String> {
+        let grant = Self {
+            issuer: issuer.public(),
+            peer,
+            ports,
+            expires,
+        };
+        let body = grant.body()?;
+        let signature = issuer.sign(&signed_message(&body));
+
+        let mut token = body;
+        token.extend_from_slice(&signature.to_bytes());
+        let token = data_encoding::BASE32_NOPAD
+            .encode(&token)
+            .to_ascii_lowercase();
+        ensure!(
+            token.len() <= MAX_TOKEN_LEN,
+            "grant is too long: the token has {} characters, servers accept at most {MAX_TOKEN_LEN}",
+            token.len()
+        );
+        Ok(token)
+    }
+
+    /// Decodes a token and checks that its signature matches the embedded issuer.
+    pub fn decode(token: &str) -> Result<Self> {
+        let token = token.trim();
+        ensure!(token.len() <= MAX_TOKEN_LEN, "grant is too long");
+        let token = data_encoding::BASE32_NOPAD
+            .decode(token.to_ascii_uppercase().as_bytes())
+            .context("grant is not valid base32")?;
+        ensure!(token.len() > Signature::LENGTH, "grant is truncated");
+        let (body, signature) = token.split_at(token.len() - Signature::LENGTH);
+        let grant = Self::parse_body(body)?;
+
+        let signature = Signature::from_bytes(signature.try_into()?);
+        grant
+            .issuer
+            .verify(&signed_message(body), &signature)
+            .context("grant signature is invalid")?;
+        Ok(grant)
+    }
+
+    /// Checks that the grant was issued by `issuer` to `peer` and has not expired.
+    pub fn check(&self, issuer: &EndpointId, peer: &EndpointId, now: SystemTime) -> Result<()> {
+        if self.issuer != *issuer {
+            bail!("grant was issued by {}, not this endpoint", self.issuer);
+        }
+        if self.peer != *peer {
+            bail!("grant was issued to {}, not {peer}", self.peer);
+        }
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        if now >= self.expires {
+            bail!("grant expired");
+        }
+        Ok(())
+    }
+
+    fn body(&self) -> Result<Vec<u8>> {
+        let ports = self
+            .ports
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut body = vec![VERSION];
+        body.extend_from_slice(self.issuer.as_bytes());
+        body.extend_from_slice(self.peer.as_bytes());
+        body.extend_from_slice(&self.expires.to_be_bytes());
+        let ports_len = u16::try_from(ports.len()).context("grant lists too many ports")?;
+        body.extend_from_slice(&ports_len.to_be_bytes());
+        body.extend_from_slice(ports.as_bytes());
+        Ok(body)
+    }
+
+    fn parse_body(body: &[u8]) -> Result<Self> {
+        let mut reader = Reader(body);
+        let version = reader.take::<1>()?[0];
+        if version != VERSION {
+            bail!("unsupported grant version {version}");
+        }
+        let issuer = PublicKey::from_bytes(&reader.take()?).context("invalid grant issuer")?;
+        let peer = PublicKey::from_bytes(&reader.take()?).context("invalid grant peer")?;
+        let expires = u64::from_be_bytes(reader.take()?);
+        let ports_len = u16::from_be_bytes(reader.take()?) as usize;
+        let ports = std::str::from_utf8(reader.take_slice(ports_len)?)
+            .context("grant ports are not valid utf-8")?
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<PortSpec>>>()
+            .context("invalid grant ports")?;
+        ensure!(reader.0.is_empty(), "grant has trailing data");
+
+        Ok(Self {
+            issuer,
+            peer,
+            ports,
+            expires,
+        })
+    }
+}
+
+fn signed_message(body: &[u8]) -> Vec<u8> {
+    [SIGNING_CONTEXT, body].concat()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into()?)
+    }
+
+    fn take_slice(&mut self, len: usize) -> Result<&[u8]> {
+        ensure!(self.0.len() >= len, "grant is truncated");
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+/// Client side of the grant exchange: sends the token and waits for the verdict.
+pub async fn present(send: &mut SendStream, recv: &mut RecvStream, token: &str) -> Result<()> {
+    let token = token.trim();
+    let len = u16::try_from(token.len()).context("grant is too long")?;
+    send.write_all(&len.to_be_bytes()).await?;
+    send.write_all(token.as_bytes()).await?;
+    send.finish()?;
+
+    let mut status = [0u8; 1];
+    recv.read_exact(&mut status)
+        .await
+        .context("remote peer closed the grant exchange")?;
+    if status[0] != ACCEPTED {
+        bail!("grant was rejected, it may be expired or issued for another endpoint");
+    }
+    Ok(())
+}
+
+/// Server side of the grant exchange: reads the presented token.
+pub async fn read_token(recv: &mut RecvStream) -> Result<String> {
+    let mut len = [0u8; 2];
+    recv.read_exact(&mut len).await?;
+    let len = u16::from_be_bytes(len) as usize;
+    ensure!(len <= MAX_TOKEN_LEN, "grant is too long");
+    let mut token = vec![0u8; len];
+    recv.read_exact(&mut token).await?;
+    String::from_utf8(token).context("grant is not valid utf-8")
+}
+
+/// Server side of the grant exchange: reports whether the grant was accepted.
+pub async fn reply(send: &mut SendStream, accepted: bool) -> Result<()> {
+    send.write_all(&[if accepted { ACCEPTED } else { REJECTED }])
+        .await?;
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn key() -> SecretKey {
+        SecretKey::generate(&mut rand::rng())
+    }
+
+    fn ports(specs: &[&str]) -> Vec<PortSpec> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn signed_grant_round_trips() {
+        let (issuer, peer) = (key(), key().public());
+        let token = Grant::sign(&issuer, peer, ports(&["22", "53/udp"]), 2_000_000_000).unwrap();
+        let grant = Grant::decode(&token).unwrap();
+
+        assert_eq!(grant.issuer, issuer.public());
+        assert_eq!(grant.peer, peer);
+        assert_eq!(grant.ports, ports(&["22", "53/udp"]));
+        assert_eq!(grant.expires, 2_000_000_000);
+        let now = UNIX_EPOCH + Duration::from_secs(1_900_000_000);
+        grant.check(&issuer.public(), &peer, now).unwrap();
+    }
+
+    #[test]
+    fn tampered_grant_is_rejected() {
+        let token = Grant::sign(&key(), key().public(), ports(&["22"]), 2_000_000_000).unwrap();
+        let mut bytes = data_encoding::BASE32_NOPAD
+            .decode(token.to_ascii_uppercase().as_bytes())
+            .unwrap();
+        // Push the expiry further out.
+        bytes[65] ^= 0x01;
+        let tampered = data_encoding::BASE32_NOPAD.encode(&bytes);
+        assert!(Grant::decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn check_rejects_wrong_issuer_peer_or_expiry() {
+        let (issuer, peer) = (key(), key().public());
+        let grant =
+            Grant::decode(&Grant::sign(&issuer, peer, ports(&["22"]), 100).unwrap()).unwrap();
+        let before = UNIX_EPOCH + Duration::from_secs(99);
+        let after = UNIX_EPOCH + Duration::from_secs(100);
+
+        assert!(grant.check(&issuer.public(), &peer, before).is_ok());
+        assert!(grant.check(&key().public(), &peer, before).is_err());
+        assert!(
+            grant
+                .check(&issuer.public(), &key().public(), before)
+                .is_err()
+        );
+        assert!(grant.check(&issuer.public(), &peer, after).is_err());
+    }
+
+    #[test]
+    fn grants_must_fit_in_a_token() {
+        let (issuer, peer) = (key(), key().public());
+        let many = |count: u16| {
+            (10_000..10_000 + count)
+                .map(|port| port.to_string().parse().unwrap())
+                .collect()
+        };
+
+        // 498 ports written as `10000/tcp` encode to just under MAX_TOKEN_LEN.
+        let token = Grant::sign(&issuer, peer, many(498), u64::MAX).unwrap();
+        assert!(token.len() <= MAX_TOKEN_LEN);
+        assert_eq!(Grant::decode(&token).unwrap().ports.len(), 498);
+
+        let err = Grant::sign(&issuer, peer, many(499), u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("too long"), "{err}");
+        let err = Grant::sign(&issuer, peer, many(7_000), u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("too many ports"), "{err}");
+    }
+}
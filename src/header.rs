@@ -0,0 +1,80 @@
+use crate::parse::{Remote, Target};
+use anyhow::{Context, Result, bail};
+use iroh::endpoint::{RecvStream, SendStream};
+use std::fmt;
+
+/// Port value in the stream header that introduces a service name or a target on
+/// another host.
+///
+/// Port 0 is never a valid target, so servers that predate named services reject these
+/// streams instead of misreading them.
+pub const NAMED_PORT: u16 = 0;
+
+/// Writes the header that opens every forwarded stream: a 2-byte big-endian port, or
+/// port 0 followed by a 1-byte length and a service name or `host:port`.
+pub async fn write(send: &mut SendStream, remote: &Remote) -> Result<()> {
+    match remote {
+        Remote::Target(Target::Inet { host: None, ports }) if ports.single_port().is_some() => {
+            send.write_all(&ports.first().to_be_bytes()).await?
+        }
+        remote => send.write_all(&encode_named(&remote.to_string())?).await?,
+    }
+    Ok(())
+}
+
+pub async fn read(recv: &mut RecvStream) -> Result<Remote> {
+    let mut port = [0u8; 2];
+    recv.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+    if port != NAMED_PORT {
+        return Ok(Remote::Target(Target::loopback(port)));
+    }
+
+    let mut len = [0u8; 1];
+    recv.read_exact(&mut len).await?;
+    let mut name = vec![0u8; len[0] as usize];
+    recv.read_exact(&mut name).await?;
+    match decode_name(&name)?.parse()? {
+        Remote::Target(target) => Ok(Remote::Target(single_port(target)?)),
+        remote => Ok(remote),
+    }
+}
+
+/// A header naming a port range, when a stream or datagram reaches a single port.
+#[derive(Debug)]
+pub struct PortRangeTarget(pub Target);
+
+impl fmt::Display for PortRangeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is a port range, not a single port", self.0)
+    }
+}
+
+impl std::error::Error for PortRangeTarget {}
+
+/// Rejects a target that covers more than one port.
+pub fn single_port(target: Target) -> Result<Target> {
+    match &target {
+        Target::Inet { ports, .. } if ports.single_port().is_none() => {
+            Err(PortRangeTarget(target).into())
+        }
+        _ => Ok(target),
+    }
+}
+
+/// Encodes port 0, a 1-byte length and `name`.
+pub fn encode_named(name: &str) -> Result<Vec<u8>> {
+    let len = u8::try_from(name.len()).context("service name or host is too long")?;
+    let mut header = Vec::with_capacity(3 + name.len());
+    header.extend_from_slice(&NAMED_PORT.to_be_bytes());
+    header.push(len);
+    header.extend_from_slice(name.as_bytes());
+    Ok(header)
+}
+
+pub fn decode_name(name: &[u8]) -> Result<&str> {
+    if name.is_empty() {
+        bail!("empty service name or host");
+    }
+    std::str::from_utf8(name).context("service name or host is not valid utf-8")
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result, bail};
+use iroh::endpoint::{RecvStream, SendStream};
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// ALPN for the pairing exchange in which a peer redeems an invitation.
+pub const PAIR_ALPN: &[u8] = b"punch/pair/0";
+
+const CODE_LEN: usize = 16;
+const PAIRED: u8 = 0;
+const REJECTED: u8 = 1;
+
+/// Single-use invitation codes minted by `punch out --invite`.
+#[derive(Debug, Default)]
+pub struct Invites {
+    expires: HashMap<String, Instant>,
+}
+
+impl Invites {
+    /// Mints `count` codes that expire after `ttl`.
+    pub fn mint(count: u32, ttl: Duration) -> Result<(Self, Vec<String>)> {
+        let expires = Instant::now()
+            .checked_add(ttl)
+            .context("duration is too long")?;
+        let mut invites = Self::default();
+        let codes: Vec<String> = (0..count)
+            .map(|_| {
+                let code = new_code();
+                invites.expires.insert(code.clone(), expires);
+                code
+            })
+            .collect();
+        Ok((invites, codes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expires.is_empty()
+    }
+
+    /// Burns `code` and reports whether it was valid and unexpired.
+    pub fn redeem(&mut self, code: &str, now: Instant) -> bool {
+        self.expires.retain(|_, expires| *expires > now);
+        self.expires.remove(code).is_some()
+    }
+}
+
+fn new_code() -> String {
+    let mut bytes = [0u8; CODE_LEN];
+    rand::rng().fill(&mut bytes);
+    data_encoding::BASE32_NOPAD
+        .encode(&bytes)
+        .to_ascii_lowercase()
+}
+
+/// Client side of the pairing exchange: sends the code and waits for the verdict.
+pub async fn present(send: &mut SendStream, recv: &mut RecvStream, code: &str) -> Result<()> {
+    let code = code.trim().to_ascii_lowercase();
+    let len = u8::try_from(code.len()).context("invitation code is too long")?;
+    send.write_all(&[len]).await?;
+    send.write_all(code.as_bytes()).await?;
+    send.finish()?;
+
+    let mut status = [0u8; 1];
+    recv.read_exact(&mut status)
+        .await
+        .context("remote peer closed the pairing exchange")?;
+    if status[0] != PAIRED {
+        bail!("invitation was rejected, it may be invalid, expired or already used");
+    }
+    Ok(())
+}
+
+/// Server side of the pairing exchange: reads the presented code.
+pub async fn read_code(recv: &mut RecvStream) -> Result<String> {
+    let mut len = [0u8; 1];
+    recv.read_exact(&mut len).await?;
+    let mut code = vec![0u8; len[0] as usize];
+    recv.read_exact(&mut code).await?;
+    String::from_utf8(code).context("invitation code is not valid utf-8")
+}
+
+/// Server side of the pairing exchange: reports whether pairing succeeded.
+pub async fn reply(send: &mut SendStream, paired: bool) -> Result<()> {
+    send.write_all(&[if paired { PAIRED } else { REJECTED }])
+        .await?;
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(10 * 60);
+
+    #[test]
+    fn codes_are_single_use() {
+        let (mut invites, codes) = Invites::mint(2, TTL).unwrap();
+        assert_eq!(codes.len(), 2);
+        assert_ne!(codes[0], codes[1]);
+
+        let now = Instant::now();
+        assert!(invites.redeem(&codes[0], now));
+        assert!(!invites.redeem(&codes[0], now));
+        assert!(invites.redeem(&codes[1], now));
+        assert!(invites.is_empty());
+    }
+
+    #[test]
+    fn expired_codes_are_rejected() {
+        let (mut invites, codes) = Invites::mint(1, Duration::from_secs(60)).unwrap();
+        let later = Instant::now() + Duration::from_secs(61);
+        assert!(!invites.redeem(&codes[0], later));
+        assert!(invites.is_empty());
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        let (mut invites, _) = Invites::mint(1, TTL).unwrap();
+        assert!(!invites.redeem("nope", Instant::now()));
+        assert!(!invites.is_empty());
+    }
+
+    #[test]
+    fn overflowing_ttls_are_rejected() {
+        assert!(Invites::mint(1, Duration::MAX).is_err());
+    }
+}
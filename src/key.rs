@@ -1,47 +1,476 @@
-use anyhow::{Context, Result, bail};
+use crate::passphrase;
+use crate::paths;
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use clap::ValueEnum;
 use iroh::SecretKey;
+use rand::Rng;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
 
-const KEY_PATH: &str = ".local/share/punch/secret.key";
+const KEY_FILE: &str = "secret.key";
+const IDENTITIES_DIR: &str = "identities";
 const KEY_LEN: usize = 32;
 
-fn key_path() -> Result<PathBuf> {
-    let home = dirs::home_dir().context("cannot determine home directory")?;
-    Ok(home.join(KEY_PATH))
+// Encrypted key file layout, all integers big-endian:
+// [magic: 8][version: u8][m_cost: u32][t_cost: u32][p_cost: u32][salt: 16][nonce: 24]
+// [ciphertext: 32][tag: 16]
+// The header up to and including the nonce is authenticated as associated data.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"punchkey";
+const ENCRYPTED_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const ENCRYPTED_HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_LEN;
+const ENCRYPTED_LEN: usize = ENCRYPTED_HEADER_LEN + KEY_LEN + TAG_LEN;
+
+/// Argon2id cost parameters stored in the encrypted key header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
 }
 
-pub fn load_or_generate() -> Result<SecretKey> {
-    let path = key_path()?;
+const DEFAULT_KDF: KdfParams = KdfParams {
+    m_cost: 64 * 1024,
+    t_cost: 3,
+    p_cost: 1,
+};
 
-    if path.exists() {
-        let bytes = fs::read(&path).context("failed to read secret key")?;
-        if bytes.len() != KEY_LEN {
+/// The most a key file may ask of Argon2id. The header is only authenticated once the
+/// key is derived, so a corrupt or tampered file must not be able to demand gigabytes of
+/// memory or hours of work first.
+const MAX_KDF: KdfParams = KdfParams {
+    m_cost: DEFAULT_KDF.m_cost * 4,
+    t_cost: DEFAULT_KDF.t_cost * 4,
+    p_cost: DEFAULT_KDF.p_cost * 4,
+};
+
+impl KdfParams {
+    fn ensure_within(self, max: KdfParams) -> Result<Self> {
+        if self.m_cost > max.m_cost || self.t_cost > max.t_cost || self.p_cost > max.p_cost {
             bail!(
-                "secret key file is {} bytes, expected {}",
-                bytes.len(),
-                KEY_LEN
+                "secret key file asks for key derivation costs of m={}, t={}, p={}, \
+                 more than the allowed m={}, t={}, p={}; the file may be corrupted",
+                self.m_cost,
+                self.t_cost,
+                self.p_cost,
+                max.m_cost,
+                max.t_cost,
+                max.p_cost
             );
         }
-        let bytes: [u8; KEY_LEN] = bytes.try_into().unwrap();
-        Ok(SecretKey::from_bytes(&bytes))
+        Ok(self)
+    }
+}
+
+/// Which secret key file a command uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    /// `secret.key` in the punch data directory.
+    Default,
+    /// `identities/<name>.key` in the punch data directory.
+    Named(String),
+    /// An explicit key file path.
+    File(PathBuf),
+}
+
+impl Identity {
+    pub fn named(name: &str) -> Result<Self> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            bail!("identity name must use only letters, digits, '-', '_' and '.', got {name:?}");
+        }
+        Ok(Identity::Named(name.to_string()))
+    }
+
+    pub fn path(&self) -> Result<PathBuf> {
+        match self {
+            Identity::Default => Ok(paths::data_dir()?.join(KEY_FILE)),
+            Identity::Named(name) => Ok(paths::data_dir()?
+                .join(IDENTITIES_DIR)
+                .join(format!("{name}.key"))),
+            Identity::File(path) => Ok(path.clone()),
+        }
+    }
+}
+
+/// Text encodings accepted by `punch key export` and `punch key import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    Hex,
+    Base32,
+}
+
+impl KeyFormat {
+    pub fn encode(self, key: &SecretKey) -> String {
+        match self {
+            KeyFormat::Hex => data_encoding::HEXLOWER.encode(&key.to_bytes()),
+            KeyFormat::Base32 => data_encoding::BASE32_NOPAD
+                .encode(&key.to_bytes())
+                .to_ascii_lowercase(),
+        }
+    }
+}
+
+pub fn load_or_generate(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        load(path)
     } else {
         let key = SecretKey::generate(&mut rand::rng());
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).context("failed to create key directory")?;
-        }
-        let mut f = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .mode(0o600)
-            .open(&path)
-            .context("failed to create secret key file")?;
-        f.write_all(&key.to_bytes())
-            .context("failed to write secret key")?;
+        write_new(path, &key.to_bytes())?;
         eprintln!("secret key created at {}", path.display());
         Ok(key)
     }
 }
+
+/// Reads an existing secret key without creating one.
+///
+/// Encrypted keys are decrypted with a passphrase from `PUNCH_KEY_PASSPHRASE` or the
+/// terminal. Legacy raw 32-byte key files are read as-is.
+pub fn load(path: &Path) -> Result<SecretKey> {
+    let bytes = read_key_file(path)?;
+    if bytes.starts_with(ENCRYPTED_MAGIC) {
+        let passphrase = passphrase::read(&format!("passphrase for {}: ", path.display()))?;
+        return decrypt(&bytes, &passphrase);
+    }
+    if bytes.len() != KEY_LEN {
+        bail!(
+            "secret key file is {} bytes, expected {}",
+            bytes.len(),
+            KEY_LEN
+        );
+    }
+    let bytes: [u8; KEY_LEN] = bytes[..].try_into().unwrap();
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+/// Reports whether the key file at `path` is passphrase-encrypted.
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    Ok(read_key_file(path)?.starts_with(ENCRYPTED_MAGIC))
+}
+
+fn read_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Zeroizing::new(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!(
+                "no secret key at {}, run `punch key generate` first",
+                path.display()
+            );
+        }
+        Err(e) => Err(e).context("failed to read secret key"),
+    }
+}
+
+/// Parses a secret key exported as hex or base32.
+pub fn decode(s: &str) -> Result<SecretKey> {
+    s.trim()
+        .parse()
+        .context("secret key must be 64 hex or 52 base32 characters")
+}
+
+/// Writes `key` to `path`, encrypted if a passphrase is given, refusing to replace an
+/// existing key unless `force` is set.
+pub fn store(path: &Path, key: &SecretKey, passphrase: Option<&str>, force: bool) -> Result<()> {
+    let bytes = encode_key_file(key, passphrase)?;
+    if !path.exists() {
+        return write_new(path, &bytes);
+    }
+    if !force {
+        bail!(
+            "secret key already exists at {}, use --force to replace it",
+            path.display()
+        );
+    }
+
+    let tmp = sibling(path, "tmp")?;
+    let _ = fs::remove_file(&tmp);
+    write_new(&tmp, &bytes)?;
+    fs::rename(&tmp, path).context("failed to replace secret key")
+}
+
+/// Replaces the key at `path` with a new one and moves the old key to a backup file.
+///
+/// Returns the new key and the backup path.
+pub fn rotate(path: &Path, passphrase: Option<&str>) -> Result<(SecretKey, PathBuf)> {
+    read_key_file(path)?;
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system clock is before the unix epoch")?
+        .as_secs();
+    let backup = sibling(path, &format!("{secs}.bak"))?;
+    if backup.exists() {
+        bail!("backup file {} already exists", backup.display());
+    }
+
+    let key = SecretKey::generate(&mut rand::rng());
+    let tmp = sibling(path, "tmp")?;
+    let _ = fs::remove_file(&tmp);
+    write_new(&tmp, &encode_key_file(&key, passphrase)?)?;
+    fs::rename(path, &backup).context("failed to back up secret key")?;
+    fs::rename(&tmp, path).context("failed to install new secret key")?;
+    Ok((key, backup))
+}
+
+fn write_new(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("failed to create key directory")?;
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .context("failed to create secret key file")?;
+    f.write_all(bytes).context("failed to write secret key")?;
+    Ok(())
+}
+
+fn encode_key_file(key: &SecretKey, passphrase: Option<&str>) -> Result<Zeroizing<Vec<u8>>> {
+    match passphrase {
+        Some(passphrase) => encrypt(key, passphrase, DEFAULT_KDF).map(Zeroizing::new),
+        None => Ok(Zeroizing::new(key.to_bytes().to_vec())),
+    }
+}
+
+fn encrypt(key: &SecretKey, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill(&mut salt);
+    rand::rng().fill(&mut nonce);
+
+    let mut data = Vec::with_capacity(ENCRYPTED_LEN);
+    data.extend_from_slice(ENCRYPTED_MAGIC);
+    data.push(ENCRYPTED_VERSION);
+    data.extend_from_slice(&kdf.m_cost.to_be_bytes());
+    data.extend_from_slice(&kdf.t_cost.to_be_bytes());
+    data.extend_from_slice(&kdf.p_cost.to_be_bytes());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+
+    let cipher = XChaCha20Poly1305::new((&*derive_key(passphrase, &salt, kdf)?).into());
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &key.to_bytes(),
+                aad: &data,
+            },
+        )
+        .map_err(|_| anyhow!("failed to encrypt secret key"))?;
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<SecretKey> {
+    let version = data.get(ENCRYPTED_MAGIC.len()).copied();
+    if version != Some(ENCRYPTED_VERSION) {
+        bail!("unsupported encrypted key version {version:?}");
+    }
+    if data.len() != ENCRYPTED_LEN {
+        bail!(
+            "encrypted key file is {} bytes, expected {}",
+            data.len(),
+            ENCRYPTED_LEN
+        );
+    }
+
+    let (header, ciphertext) = data.split_at(ENCRYPTED_HEADER_LEN);
+    let field = |offset: usize| {
+        let start = ENCRYPTED_MAGIC.len() + 1 + offset * 4;
+        u32::from_be_bytes(header[start..start + 4].try_into().unwrap())
+    };
+    let kdf = KdfParams {
+        m_cost: field(0),
+        t_cost: field(1),
+        p_cost: field(2),
+    }
+    .ensure_within(MAX_KDF)?;
+    let salt = &header[ENCRYPTED_HEADER_LEN - NONCE_LEN - SALT_LEN..][..SALT_LEN];
+    let nonce = &header[ENCRYPTED_HEADER_LEN - NONCE_LEN..];
+
+    let cipher = XChaCha20Poly1305::new((&*derive_key(passphrase, salt, kdf)?).into());
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| anyhow!("wrong passphrase or corrupted secret key file"))?,
+    );
+    let bytes: [u8; KEY_LEN] = plaintext[..].try_into().unwrap();
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| anyhow!("invalid key derivation parameters: {e}"))?;
+    let mut derived = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *derived)
+        .map_err(|e| anyhow!("failed to derive key from passphrase: {e}"))?;
+    Ok(derived)
+}
+
+/// Returns `<path>.<suffix>` next to the key file.
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .context("secret key path has no file name")?
+        .to_string_lossy();
+    Ok(path.with_file_name(format!("{name}.{suffix}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_key_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "punch-key-{name}-{}",
+            SecretKey::generate(&mut rand::rng()).public().fmt_short()
+        ));
+        dir.join("secret.key")
+    }
+
+    #[test]
+    fn export_formats_roundtrip_through_decode() {
+        let key = SecretKey::generate(&mut rand::rng());
+        for format in [KeyFormat::Hex, KeyFormat::Base32] {
+            let decoded = decode(&format.encode(&key)).unwrap();
+            assert_eq!(decoded.to_bytes(), key.to_bytes());
+        }
+        assert!(decode("not-a-key").is_err());
+    }
+
+    #[test]
+    fn store_requires_force_to_replace() {
+        let path = temp_key_path("store");
+        let first = SecretKey::generate(&mut rand::rng());
+        let second = SecretKey::generate(&mut rand::rng());
+
+        store(&path, &first, None, false).unwrap();
+        assert!(store(&path, &second, None, false).is_err());
+        store(&path, &second, None, true).unwrap();
+
+        assert_eq!(load(&path).unwrap().to_bytes(), second.to_bytes());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotate_keeps_old_key_as_backup() {
+        let path = temp_key_path("rotate");
+        let old = SecretKey::generate(&mut rand::rng());
+        store(&path, &old, None, false).unwrap();
+
+        let (new, backup) = rotate(&path, None).unwrap();
+        assert_eq!(load(&path).unwrap().to_bytes(), new.to_bytes());
+        assert_eq!(load(&backup).unwrap().to_bytes(), old.to_bytes());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn encrypted_key_roundtrip() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let data = encrypt(&key, "hunter2", TEST_KDF).unwrap();
+        assert_eq!(data.len(), ENCRYPTED_LEN);
+        assert!(data.starts_with(ENCRYPTED_MAGIC));
+        assert_eq!(
+            decrypt(&data, "hunter2").unwrap().to_bytes(),
+            key.to_bytes()
+        );
+    }
+
+    #[test]
+    fn encrypted_key_rejects_wrong_passphrase_and_tampering() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let data = encrypt(&key, "hunter2", TEST_KDF).unwrap();
+        assert!(decrypt(&data, "hunter3").is_err());
+
+        let mut tampered = data.clone();
+        tampered[ENCRYPTED_MAGIC.len() + 2] ^= 1;
+        assert!(decrypt(&tampered, "hunter2").is_err());
+
+        let mut future = data;
+        future[ENCRYPTED_MAGIC.len()] = ENCRYPTED_VERSION + 1;
+        let err = decrypt(&future, "hunter2").unwrap_err();
+        assert!(err.to_string().contains("unsupported"));
+    }
+
+    #[test]
+    fn encrypted_key_rejects_excessive_kdf_costs() {
+        let key = SecretKey::generate(&mut rand::rng());
+        assert!(DEFAULT_KDF.ensure_within(MAX_KDF).is_ok());
+        assert!(MAX_KDF.ensure_within(MAX_KDF).is_ok());
+
+        let data = encrypt(&key, "hunter2", TEST_KDF).unwrap();
+        for field in 0..3 {
+            let mut tampered = data.clone();
+            let start = ENCRYPTED_MAGIC.len() + 1 + field * 4;
+            tampered[start..start + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+            let err = decrypt(&tampered, "hunter2").unwrap_err();
+            assert!(err.to_string().contains("may be corrupted"), "{err}");
+        }
+    }
+
+    #[test]
+    fn legacy_raw_key_is_not_encrypted() {
+        let path = temp_key_path("legacy");
+        let key = SecretKey::generate(&mut rand::rng());
+        store(&path, &key, None, false).unwrap();
+        assert!(!is_encrypted(&path).unwrap());
+        assert_eq!(load(&path).unwrap().to_bytes(), key.to_bytes());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn identity_names_are_validated() {
+        assert!(Identity::named("web-prod_1.a").is_ok());
+        assert!(Identity::named("").is_err());
+        assert!(Identity::named(".hidden").is_err());
+        assert!(Identity::named("../escape").is_err());
+        assert!(Identity::named("a/b").is_err());
+    }
+
+    #[test]
+    fn load_or_generate_creates_then_reuses_key() {
+        let path = temp_key_path("load-or-generate");
+        let created = load_or_generate(&path).unwrap();
+        let loaded = load_or_generate(&path).unwrap();
+        assert_eq!(created.to_bytes(), loaded.to_bytes());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_reports_missing_key() {
+        let path = temp_key_path("missing");
+        let err = load(&path).unwrap_err();
+        assert!(err.to_string().contains("punch key generate"));
+    }
+}
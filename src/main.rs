@@ -1,61 +1,645 @@
+mod authorized;
 mod client;
+mod config;
+mod control;
+mod dest;
+mod grant;
+mod header;
+mod invite;
 mod key;
+mod mirror;
 mod parse;
+mod passphrase;
+mod paths;
+mod peers;
+mod policy;
 mod proxy;
+mod rejection;
+mod seed;
 mod server;
 mod stdio;
+mod stream;
 mod udp;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use iroh::EndpointId;
+use anyhow::{Context, Result, bail};
+use authorized::AuthorizedPeers;
+use clap::{Args, Parser, Subcommand};
+use client::Forwards;
+use config::Config;
+use dest::DestRule;
+use invite::Invites;
+use iroh::{EndpointId, SecretKey};
+use key::{Identity, KeyFormat};
+use mirror::{LocalPorts, Mirror};
+use parse::{Exposure, Mapping, PortRange};
+use peers::{KnownPeer, KnownPeers};
+use policy::Policy;
+use rejection::Rejection;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(about = "Peer-to-peer TCP and UDP port forwarding over iroh")]
-enum Cli {
+struct Cli {
+    /// Config file (default: punch.toml in the punch config directory)
+    #[arg(long, global = true, env = "PUNCH_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    identity: IdentityArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct IdentityArgs {
+    /// Use a named identity from the punch data directory
+    #[arg(long, global = true, env = "PUNCH_IDENTITY", value_name = "NAME")]
+    identity: Option<String>,
+    /// Use the secret key at this path (overrides --identity)
+    #[arg(long, global = true, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Use a new in-memory key that is never written to disk
+    #[arg(long, global = true)]
+    ephemeral: bool,
+}
+
+impl IdentityArgs {
+    /// Resolves the identity from the flags, falling back to the config file.
+    fn resolve(&self, config: &Config) -> Result<Identity> {
+        match self.flagged()? {
+            Some(identity) => Ok(identity),
+            None => Ok(config.identity.clone().unwrap_or(Identity::Default)),
+        }
+    }
+
+    /// Resolves the identity, reading the config file at `config_path` only when no flag
+    /// names one. A broken config file can then be worked around with a flag.
+    fn resolve_lazily(&self, config_path: Option<&Path>) -> Result<Identity> {
+        match self.flagged()? {
+            Some(identity) => Ok(identity),
+            None => self.resolve(&Config::load(config_path)?),
+        }
+    }
+
+    /// The identity named by `--key-file` or `--identity`, if any.
+    fn flagged(&self) -> Result<Option<Identity>> {
+        match (&self.key_file, &self.identity) {
+            (Some(path), _) => Ok(Some(Identity::File(path.clone()))),
+            (None, Some(name)) => Identity::named(name).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Returns the secret key for `punch out` and `punch in`.
+    fn secret_key(&self, config: &Config) -> Result<SecretKey> {
+        if self.ephemeral {
+            let secret_key = SecretKey::generate(&mut rand::rng());
+            eprintln!("using ephemeral identity {}", secret_key.public());
+            return Ok(secret_key);
+        }
+        key::load_or_generate(&self.resolve(config)?.path()?)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
     /// Expose local ports to remote peers
     Out {
-        /// Ports to expose (e.g. 8080 53/udp)
-        #[arg(required = true)]
+        /// Ports to expose (e.g. 8080 53/udp ssh=22), defaults to the config file's ports
         ports: Vec<String>,
+        /// Only accept this peer's endpoint ID (repeatable)
+        #[arg(long = "allow", value_name = "ENDPOINT_ID")]
+        allow: Vec<String>,
+        /// Let peers choose destinations matching this rule (e.g. 10.0.0.0/24:5432
+        /// *.internal:443), repeatable
+        #[arg(long = "allow-dest", value_name = "HOST:PORT")]
+        allow_dest: Vec<String>,
+        /// Let peers with access to every port ask to listen on these ports of 127.0.0.1
+        /// for `punch in --reverse` (e.g. 9000-9100), repeatable
+        #[arg(long = "allow-bind", value_name = "PORTS")]
+        allow_bind: Vec<String>,
+        /// Authorized keys file (default: authorized_keys in the punch data directory)
+        #[arg(long, value_name = "PATH")]
+        authorized_keys: Option<PathBuf>,
+        /// Print a single-use invitation code that adds a peer to the authorized keys
+        #[arg(long)]
+        invite: bool,
+        /// Number of invitation codes to print with --invite
+        #[arg(long, value_name = "COUNT", default_value_t = 1, requires = "invite")]
+        invite_count: u32,
+        /// How long invitation codes stay valid (e.g. 30s, 10m, 1h)
+        #[arg(long, value_name = "DURATION", default_value = "10m")]
+        invite_ttl: String,
     },
     /// Connect to a remote peer
     In {
-        /// Remote peer's endpoint ID (base32)
+        /// Redeem an invitation code from `punch out --invite` before connecting
+        #[arg(long, value_name = "CODE")]
+        invite: Option<String>,
+        /// Present a grant from `punch grant` to reach the ports it lists
+        #[arg(long, value_name = "TOKEN")]
+        grant: Option<String>,
+        /// Write the address each mapping listens on to this file, one `<local>\t<remote>`
+        /// line per mapping, once every listener is bound
+        #[arg(long, value_name = "PATH")]
+        report_file: Option<PathBuf>,
+        /// Mirror every port the remote peer offers, following changes to what it offers
+        #[arg(long, conflicts_with = "mappings")]
+        all: bool,
+        /// With --all, listen on each remote port plus this offset (default: 0)
+        #[arg(
+            long,
+            value_name = "N",
+            requires = "all",
+            conflicts_with = "prefix_range"
+        )]
+        offset: Option<u16>,
+        /// With --all, listen on the lowest free ports of this range (e.g. 20000-20999)
+        #[arg(long, value_name = "FIRST-LAST")]
+        prefix_range: Option<String>,
+        /// Have the remote peer listen on a port of its 127.0.0.1 and forward connections
+        /// to a local port (e.g. 9000:3000), repeatable
+        #[arg(long, value_name = "REMOTE:LOCAL")]
+        reverse: Vec<String>,
+        /// Remote peer's endpoint ID (base32), or the name of a known peer
+        pubkey: String,
+        /// Mappings (e.g. 4000:8080 5300:53/udp -:22), defaults to the known peer's mappings
+        #[arg(allow_hyphen_values = true)]
+        mappings: Vec<String>,
+    },
+    /// List the ports and services a remote peer lets this identity reach
+    Ls {
+        /// Present a grant from `punch grant` to list the ports it adds
+        #[arg(long, value_name = "TOKEN")]
+        grant: Option<String>,
+        /// Remote peer's endpoint ID (base32), or the name of a known peer
         pubkey: String,
-        /// Mappings (e.g. 4000:8080 5300:53/udp -:22)
-        #[arg(required = true, allow_hyphen_values = true)]
+    },
+    /// Sign a grant that lets a peer reach ports exposed by this identity
+    Grant {
+        /// Endpoint ID of the peer receiving the grant
+        peer: String,
+        /// Ports the peer may reach (e.g. 22 53/udp)
+        #[arg(required = true)]
+        ports: Vec<String>,
+        /// When the grant stops being valid, as YYYY-MM-DD (inclusive) or a duration (e.g. 30d)
+        #[arg(long, value_name = "DATE|DURATION")]
+        expires: String,
+    },
+    /// Manage this machine's identity
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage named peers for `punch in`
+    Peers {
+        #[command(subcommand)]
+        command: PeersCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    /// Save a peer under a name, with optional default mappings
+    Add {
+        /// Name to use with `punch in`
+        name: String,
+        /// The peer's endpoint ID
+        endpoint_id: String,
+        /// Mappings used when `punch in` is given none
+        #[arg(allow_hyphen_values = true)]
         mappings: Vec<String>,
+        /// Replace an existing peer with the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// Forget a named peer
+    Rm { name: String },
+    /// List named peers
+    Ls,
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Print the endpoint ID of the secret key
+    Show,
+    /// Create a new secret key
+    Generate {
+        /// Replace an existing secret key
+        #[arg(long)]
+        force: bool,
+        /// Encrypt the key with a passphrase
+        #[arg(long)]
+        encrypt: bool,
+        /// Derive the key from a new seed phrase and print the phrase
+        #[arg(long)]
+        mnemonic: bool,
+        /// Derive the sub-identity with this label from the seed phrase
+        #[arg(long, requires = "mnemonic")]
+        label: Option<String>,
+    },
+    /// Restore a secret key from a seed phrase read from stdin
+    Recover {
+        /// Derive the sub-identity with this label
+        #[arg(long)]
+        label: Option<String>,
+        /// Replace an existing secret key
+        #[arg(long)]
+        force: bool,
+        /// Encrypt the key with a passphrase
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Print the secret key to stdout
+    Export {
+        #[arg(long, value_enum, default_value_t = KeyFormat::Hex)]
+        format: KeyFormat,
+    },
+    /// Install a secret key exported as hex or base32
+    Import {
+        /// The secret key (read from stdin if omitted)
+        key: Option<String>,
+        /// Replace an existing secret key
+        #[arg(long)]
+        force: bool,
+        /// Encrypt the key with a passphrase
+        #[arg(long)]
+        encrypt: bool,
     },
+    /// Replace the secret key, keeping the old one as a backup
+    Rotate {
+        /// Encrypt the new key with a passphrase (implied if the old key is encrypted)
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Encrypt the secret key file with a passphrase
+    Encrypt,
+    /// Store the secret key file unencrypted
+    Decrypt,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    match Cli::parse() {
-        Cli::Out { ports } => {
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            // A rejected stdio stream exits with a code saying why, for scripts.
+            let rejection = e.downcast_ref::<Rejection>();
+            ExitCode::from(rejection.map_or(1, |rejection| rejection.exit_code()))
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    // Key and peer commands leave the config file alone, so they keep working while a
+    // broken one is fixed.
+    match cli.command {
+        Command::Out {
+            ports,
+            allow,
+            allow_dest,
+            allow_bind,
+            authorized_keys,
+            invite,
+            invite_count,
+            invite_ttl,
+        } => {
+            let sources = OutSources {
+                ports: parse::parse_exposures(&ports)?,
+                allow: allow
+                    .iter()
+                    .map(|peer| peer.parse().context("invalid --allow endpoint ID"))
+                    .collect::<Result<_>>()?,
+                dest_rules: allow_dest
+                    .iter()
+                    .map(|rule| {
+                        rule.parse()
+                            .with_context(|| format!("invalid --allow-dest {rule}"))
+                    })
+                    .collect::<Result<_>>()?,
+                bind_ports: allow_bind
+                    .iter()
+                    .map(|ports| {
+                        ports
+                            .parse()
+                            .with_context(|| format!("invalid --allow-bind {ports}"))
+                    })
+                    .collect::<Result<_>>()?,
+                authorized_keys,
+                // Inviting peers only makes sense if uninvited peers are turned away.
+                open_by_default: !invite,
+            };
+            let config = Config::load(cli.config.as_deref())?;
+            let policy = sources.policy(&config)?;
+            let invite_ttl = parse::parse_duration(&invite_ttl).context("invalid --invite-ttl")?;
+            let secret_key = cli.identity.secret_key(&config)?;
+            let (invites, codes) = Invites::mint(if invite { invite_count } else { 0 }, invite_ttl)
+                .context("invalid --invite-ttl")?;
+            for code in codes {
+                eprintln!(
+                    "invite: {code} (single use, expires in {}s)",
+                    invite_ttl.as_secs()
+                );
+            }
+            let config_path = cli.config;
+            server::run(policy, invites, secret_key, move || {
+                sources.policy(&Config::load(config_path.as_deref())?)
+            })
+            .await
+        }
+        Command::In {
+            invite,
+            grant,
+            report_file,
+            all,
+            offset,
+            prefix_range,
+            reverse,
+            pubkey,
+            mappings,
+        } => {
+            let config = Config::load(cli.config.as_deref())?;
+            let (endpoint_id, default_mappings) = resolve_peer(&pubkey, &config)?;
+            let mirror = match (all, prefix_range) {
+                (false, None) if offset.is_none() => None,
+                (false, _) => bail!("--offset and --prefix-range need --all"),
+                (true, Some(range)) => Some(LocalPorts::Range(
+                    range.parse().context("invalid --prefix-range")?,
+                )),
+                (true, None) => Some(LocalPorts::Offset(offset.unwrap_or(0))),
+            };
+            let mappings = if all {
+                Vec::new()
+            } else if mappings.is_empty() && reverse.is_empty() {
+                default_mappings
+            } else {
+                parse::parse_mappings(&mappings)?
+            };
+            let reverse = parse::parse_reverses(&reverse)?;
+            if mappings.is_empty() && reverse.is_empty() && !all {
+                bail!("no mappings given and {pubkey} has no default mappings");
+            }
+            let secret_key = cli.identity.secret_key(&config)?;
+            let forwards = Forwards {
+                mappings,
+                mirror: mirror.map(Mirror::new),
+                reverse,
+            };
+            client::run(
+                endpoint_id,
+                forwards,
+                secret_key,
+                invite,
+                grant,
+                report_file,
+            )
+            .await
+        }
+        Command::Ls { grant, pubkey } => {
+            let config = Config::load(cli.config.as_deref())?;
+            let (endpoint_id, _) = resolve_peer(&pubkey, &config)?;
+            let secret_key = cli.identity.secret_key(&config)?;
+            client::list(endpoint_id, secret_key, grant).await
+        }
+        Command::Grant {
+            peer,
+            ports,
+            expires,
+        } => {
+            if cli.identity.ephemeral {
+                bail!("--ephemeral cannot be used with punch grant");
+            }
+            let peer: EndpointId = peer.parse().context("invalid endpoint ID")?;
             let ports = parse::parse_ports(&ports)?;
-            let secret_key = key::load_or_generate()?;
-            server::run(ports, secret_key).await
+            let expires =
+                parse::parse_expiry(&expires, SystemTime::now()).context("invalid --expires")?;
+            let config = Config::load(cli.config.as_deref())?;
+            let secret_key = key::load(&cli.identity.resolve(&config)?.path()?)?;
+            println!("{}", grant::Grant::sign(&secret_key, peer, ports, expires)?);
+            Ok(())
+        }
+        Command::Key { command } => {
+            if cli.identity.ephemeral {
+                bail!("--ephemeral cannot be used with punch key");
+            }
+            let identity = cli.identity.resolve_lazily(cli.config.as_deref())?;
+            run_key_command(command, &identity.path()?)
+        }
+        Command::Peers { command } => run_peers_command(command, &peers::default_path()?),
+    }
+}
+
+/// Resolves the peer given to `punch in` or `punch ls` to its endpoint ID and default
+/// mappings. Peers named in the config file take precedence over known peers.
+fn resolve_peer(pubkey: &str, config: &Config) -> Result<(EndpointId, Vec<Mapping>)> {
+    let known = match peers::default_path() {
+        Ok(path) => KnownPeers::load(&path)?,
+        // Without a home directory only endpoint IDs can be used.
+        Err(_) => KnownPeers::default(),
+    };
+    match config.peers.get(pubkey) {
+        Some(configured) => {
+            let (id, _) = peers::resolve(&configured.peer, &known)
+                .with_context(|| format!("invalid peer for [in.{pubkey}]"))?;
+            Ok((id, configured.mappings.clone()))
+        }
+        None => {
+            let (id, mappings) = peers::resolve(pubkey, &known)?;
+            Ok((id, parse::parse_mappings(&mappings)?))
         }
-        Cli::In { pubkey, mappings } => {
-            let endpoint_id: EndpointId = pubkey.parse().context("invalid endpoint ID")?;
-            let mappings = parse::parse_mappings(&mappings)?;
-            let secret_key = key::load_or_generate()?;
-            client::run(endpoint_id, mappings, secret_key).await
+    }
+}
+
+/// The command line arguments `punch out` builds its policy from, together with the
+/// config file and the authorized keys file.
+struct OutSources {
+    /// Ports from the command line. The config file's ports are used when empty.
+    ports: Vec<Exposure>,
+    allow: Vec<EndpointId>,
+    dest_rules: Vec<DestRule>,
+    bind_ports: Vec<PortRange>,
+    authorized_keys: Option<PathBuf>,
+    open_by_default: bool,
+}
+
+impl OutSources {
+    /// Builds the policy from the current contents of the config and authorized keys
+    /// files.
+    fn policy(&self, config: &Config) -> Result<Policy> {
+        let ports = if self.ports.is_empty() {
+            config.out.ports.clone()
+        } else {
+            self.ports.clone()
+        };
+        if ports.is_empty() && self.dest_rules.is_empty() && self.bind_ports.is_empty() {
+            bail!("no ports given and none configured in [out]");
         }
+        let allow: Vec<EndpointId> = self
+            .allow
+            .iter()
+            .chain(&config.out.allow)
+            .copied()
+            .collect();
+        let authorized_keys = self
+            .authorized_keys
+            .as_deref()
+            .or(config.out.authorized_keys.as_deref());
+        let authorized = AuthorizedPeers::load(authorized_keys, &allow, self.open_by_default)?;
+        let policy = Policy::new(ports, authorized)
+            .with_dest_rules(self.dest_rules.clone())
+            .with_bind_ports(self.bind_ports.clone());
+        policy.check_open_rules()?;
+        Ok(policy)
     }
 }
 
+fn run_peers_command(command: PeersCommand, path: &Path) -> Result<()> {
+    let mut known = KnownPeers::load(path)?;
+    match command {
+        PeersCommand::Add {
+            name,
+            endpoint_id,
+            mappings,
+            force,
+        } => {
+            let id: EndpointId = endpoint_id.parse().context("invalid endpoint ID")?;
+            known.add(KnownPeer::new(&name, id, mappings)?, force)?;
+            known.save()?;
+        }
+        PeersCommand::Rm { name } => {
+            known.remove(&name)?;
+            known.save()?;
+        }
+        PeersCommand::Ls => {
+            for peer in known.iter() {
+                println!("{}\t{}\t{}", peer.name, peer.id, peer.mappings.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_key_command(command: KeyCommand, path: &Path) -> Result<()> {
+    match command {
+        KeyCommand::Show => {
+            println!("{}", key::load(path)?.public());
+        }
+        KeyCommand::Generate {
+            force,
+            encrypt,
+            mnemonic,
+            label,
+        } => {
+            let passphrase = encrypt.then(passphrase::read_new).transpose()?;
+            let secret_key = if mnemonic {
+                let mnemonic = seed::generate();
+                eprintln!("seed phrase, write it down to recover this identity:");
+                eprintln!("{mnemonic}");
+                seed::derive(&mnemonic, label.as_deref().unwrap_or_default())
+            } else {
+                SecretKey::generate(&mut rand::rng())
+            };
+            key::store(
+                path,
+                &secret_key,
+                passphrase.as_deref().map(|p| p.as_str()),
+                force,
+            )?;
+            eprintln!("secret key created at {}", path.display());
+            println!("{}", secret_key.public());
+        }
+        KeyCommand::Export { format } => {
+            println!("{}", format.encode(&key::load(path)?));
+        }
+        KeyCommand::Import {
+            key,
+            force,
+            encrypt,
+        } => {
+            let key = match key {
+                Some(key) => key,
+                None => {
+                    let mut key = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut key)
+                        .context("failed to read secret key from stdin")?;
+                    key
+                }
+            };
+            let secret_key = key::decode(&key)?;
+            let passphrase = encrypt.then(passphrase::read_new).transpose()?;
+            key::store(
+                path,
+                &secret_key,
+                passphrase.as_deref().map(|p| p.as_str()),
+                force,
+            )?;
+            eprintln!("secret key imported to {}", path.display());
+            println!("{}", secret_key.public());
+        }
+        KeyCommand::Recover {
+            label,
+            force,
+            encrypt,
+        } => {
+            let mut phrase = Zeroizing::new(String::new());
+            std::io::stdin()
+                .read_to_string(&mut phrase)
+                .context("failed to read seed phrase from stdin")?;
+            let mnemonic = seed::parse(&phrase)?;
+            let secret_key = seed::derive(&mnemonic, label.as_deref().unwrap_or_default());
+            let passphrase = encrypt.then(passphrase::read_new).transpose()?;
+            key::store(
+                path,
+                &secret_key,
+                passphrase.as_deref().map(|p| p.as_str()),
+                force,
+            )?;
+            eprintln!("secret key recovered to {}", path.display());
+            println!("{}", secret_key.public());
+        }
+        KeyCommand::Rotate { encrypt } => {
+            let old = key::load(path)?.public();
+            let passphrase = (encrypt || key::is_encrypted(path)?)
+                .then(passphrase::read_new)
+                .transpose()?;
+            let (secret_key, backup) =
+                key::rotate(path, passphrase.as_deref().map(|p| p.as_str()))?;
+            eprintln!("old key {old} moved to {}", backup.display());
+            println!("{}", secret_key.public());
+        }
+        KeyCommand::Encrypt => {
+            let secret_key = key::load(path)?;
+            let passphrase = passphrase::read_new()?;
+            key::store(path, &secret_key, Some(&passphrase), true)?;
+            eprintln!("secret key at {} is now encrypted", path.display());
+        }
+        KeyCommand::Decrypt => {
+            let secret_key = key::load(path)?;
+            key::store(path, &secret_key, None, true)?;
+            eprintln!("secret key at {} is now unencrypted", path.display());
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{Cli, Command, KeyCommand, KeyFormat, PeersCommand};
+    use crate::config::Config;
+    use crate::key::Identity;
     use clap::Parser;
+    use std::path::PathBuf;
 
     #[test]
     fn cli_accepts_stdio_mapping_without_double_dash() {
         let cli = Cli::try_parse_from(["punch", "in", "peer", "-:22"]).unwrap();
-        match cli {
-            Cli::In { mappings, .. } => assert_eq!(mappings, vec!["-:22"]),
+        match cli.command {
+            Command::In { mappings, .. } => assert_eq!(mappings, vec!["-:22"]),
             _ => panic!("expected in subcommand"),
         }
     }
@@ -64,19 +648,325 @@ mod tests {
     fn cli_accepts_mixed_mappings_with_stdio() {
         let cli = Cli::try_parse_from(["punch", "in", "peer", "-:22", "3000:8080", "5300:53/udp"])
             .unwrap();
-        match cli {
-            Cli::In { mappings, .. } => {
+        match cli.command {
+            Command::In { mappings, .. } => {
                 assert_eq!(mappings, vec!["-:22", "3000:8080", "5300:53/udp"])
             }
             _ => panic!("expected in subcommand"),
         }
     }
 
+    #[test]
+    fn cli_parses_ls_with_a_grant() {
+        let cli = Cli::try_parse_from(["punch", "ls", "--grant", "token", "peer"]).unwrap();
+        match cli.command {
+            Command::Ls { grant, pubkey } => {
+                assert_eq!(grant.as_deref(), Some("token"));
+                assert_eq!(pubkey, "peer");
+            }
+            _ => panic!("expected ls subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_reverse_and_allow_bind_repeat() {
+        let cli = Cli::try_parse_from([
+            "punch",
+            "in",
+            "--reverse",
+            "9000:3000",
+            "--reverse",
+            "9001:3001",
+            "peer",
+        ])
+        .unwrap();
+        match cli.command {
+            Command::In {
+                reverse, mappings, ..
+            } => {
+                assert_eq!(reverse, ["9000:3000", "9001:3001"]);
+                assert!(mappings.is_empty());
+            }
+            _ => panic!("expected in subcommand"),
+        }
+        let cli = Cli::try_parse_from(["punch", "out", "--allow-bind", "9000-9100"]).unwrap();
+        match cli.command {
+            Command::Out { allow_bind, .. } => assert_eq!(allow_bind, ["9000-9100"]),
+            _ => panic!("expected out subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_all_takes_no_mappings_and_one_port_choice() {
+        let cli =
+            Cli::try_parse_from(["punch", "in", "--all", "--offset", "10000", "peer"]).unwrap();
+        match cli.command {
+            Command::In {
+                all,
+                offset,
+                mappings,
+                ..
+            } => {
+                assert!(all);
+                assert_eq!(offset, Some(10000));
+                assert!(mappings.is_empty());
+            }
+            _ => panic!("expected in subcommand"),
+        }
+        assert!(Cli::try_parse_from(["punch", "in", "--all", "peer", "3000:8080"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "punch",
+                "in",
+                "--all",
+                "--offset",
+                "1",
+                "--prefix-range",
+                "20000-20999",
+                "peer",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn cli_accepts_repeated_allow_flags() {
+        let cli =
+            Cli::try_parse_from(["punch", "out", "22", "--allow", "a", "--allow", "b"]).unwrap();
+        match cli.command {
+            Command::Out { ports, allow, .. } => {
+                assert_eq!(ports, vec!["22"]);
+                assert_eq!(allow, vec!["a", "b"]);
+            }
+            _ => panic!("expected out subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_parses_key_subcommands() {
+        let cli = Cli::try_parse_from(["punch", "key", "export", "--format", "base32"]).unwrap();
+        match cli.command {
+            Command::Key {
+                command: KeyCommand::Export { format },
+            } => assert_eq!(format, KeyFormat::Base32),
+            _ => panic!("expected key export subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(["punch", "key", "generate", "--force"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Key {
+                command: KeyCommand::Generate {
+                    force: true,
+                    encrypt: false,
+                    mnemonic: false,
+                    label: None,
+                }
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["punch", "key", "generate", "--mnemonic", "--label", "web"])
+            .unwrap();
+        match cli.command {
+            Command::Key {
+                command:
+                    KeyCommand::Generate {
+                        mnemonic, label, ..
+                    },
+            } => {
+                assert!(mnemonic);
+                assert_eq!(label.as_deref(), Some("web"));
+            }
+            _ => panic!("expected key generate subcommand"),
+        }
+        assert!(Cli::try_parse_from(["punch", "key", "generate", "--label", "web"]).is_err());
+
+        let cli = Cli::try_parse_from(["punch", "key", "recover", "--label", "db"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Key {
+                command: KeyCommand::Recover { label: Some(_), .. }
+            }
+        ));
+    }
+
+    #[test]
+    fn cli_identity_flags_are_global() {
+        let cli =
+            Cli::try_parse_from(["punch", "in", "--identity", "web", "peer", "3000:80"]).unwrap();
+        assert_eq!(
+            cli.identity.resolve(&Config::default()).unwrap(),
+            Identity::Named("web".into())
+        );
+
+        let cli = Cli::try_parse_from([
+            "punch",
+            "--identity",
+            "web",
+            "--key-file",
+            "/tmp/k",
+            "key",
+            "show",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.identity.resolve(&Config::default()).unwrap(),
+            Identity::File(PathBuf::from("/tmp/k"))
+        );
+    }
+
+    #[test]
+    fn cli_identity_falls_back_to_config() {
+        let config = Config {
+            identity: Some(Identity::Named("db".into())),
+            ..Config::default()
+        };
+        let cli = Cli::try_parse_from(["punch", "key", "show"]).unwrap();
+        assert_eq!(
+            cli.identity.resolve(&config).unwrap(),
+            Identity::Named("db".into())
+        );
+
+        let cli = Cli::try_parse_from(["punch", "--identity", "web", "key", "show"]).unwrap();
+        assert_eq!(
+            cli.identity.resolve(&config).unwrap(),
+            Identity::Named("web".into())
+        );
+    }
+
+    #[test]
+    fn cli_identity_flags_bypass_a_broken_config() {
+        let config = std::env::temp_dir().join("punch-missing-config.toml");
+        let cli = Cli::try_parse_from(["punch", "--identity", "web", "key", "show"]).unwrap();
+        assert_eq!(
+            cli.identity.resolve_lazily(Some(&config)).unwrap(),
+            Identity::Named("web".into())
+        );
+
+        let cli = Cli::try_parse_from(["punch", "key", "show"]).unwrap();
+        assert!(cli.identity.resolve_lazily(Some(&config)).is_err());
+    }
+
+    #[test]
+    fn cli_out_ports_may_come_from_config() {
+        let cli = Cli::try_parse_from(["punch", "--config", "/tmp/punch.toml", "out"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("/tmp/punch.toml")));
+        match cli.command {
+            Command::Out { ports, .. } => assert!(ports.is_empty()),
+            _ => panic!("expected out subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_parses_grant_subcommand() {
+        let cli = Cli::try_parse_from([
+            "punch",
+            "grant",
+            "peer",
+            "22",
+            "5432",
+            "--expires",
+            "2026-12-31",
+        ])
+        .unwrap();
+        match cli.command {
+            Command::Grant {
+                peer,
+                ports,
+                expires,
+            } => {
+                assert_eq!(peer, "peer");
+                assert_eq!(ports, vec!["22", "5432"]);
+                assert_eq!(expires, "2026-12-31");
+            }
+            _ => panic!("expected grant subcommand"),
+        }
+
+        assert!(Cli::try_parse_from(["punch", "grant", "peer", "22"]).is_err());
+        let cli =
+            Cli::try_parse_from(["punch", "in", "--grant", "token", "peer", "3000:22"]).unwrap();
+        match cli.command {
+            Command::In { grant, .. } => assert_eq!(grant.as_deref(), Some("token")),
+            _ => panic!("expected in subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_parses_known_peer_commands() {
+        let cli = Cli::try_parse_from(["punch", "in", "prod-db"]).unwrap();
+        match cli.command {
+            Command::In {
+                pubkey, mappings, ..
+            } => {
+                assert_eq!(pubkey, "prod-db");
+                assert!(mappings.is_empty());
+            }
+            _ => panic!("expected in subcommand"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["punch", "peers", "add", "--force", "db", "id", "-:22"]).unwrap();
+        match cli.command {
+            Command::Peers {
+                command:
+                    PeersCommand::Add {
+                        name,
+                        mappings,
+                        force,
+                        ..
+                    },
+            } => {
+                assert_eq!(name, "db");
+                assert_eq!(mappings, vec!["-:22"]);
+                assert!(force);
+            }
+            _ => panic!("expected peers add subcommand"),
+        }
+        assert!(Cli::try_parse_from(["punch", "peers", "rm"]).is_err());
+    }
+
+    #[test]
+    fn cli_parses_invite_flags() {
+        let cli = Cli::try_parse_from(["punch", "out", "--invite", "22"]).unwrap();
+        match cli.command {
+            Command::Out {
+                invite,
+                invite_count,
+                ports,
+                ..
+            } => {
+                assert!(invite);
+                assert_eq!(invite_count, 1);
+                assert_eq!(ports, vec!["22"]);
+            }
+            _ => panic!("expected out subcommand"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["punch", "out", "22", "--invite", "--invite-count", "3"]).unwrap();
+        match cli.command {
+            Command::Out { invite_count, .. } => assert_eq!(invite_count, 3),
+            _ => panic!("expected out subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(["punch", "in", "--invite", "code", "peer", "-:22"]).unwrap();
+        match cli.command {
+            Command::In { invite, .. } => assert_eq!(invite.as_deref(), Some("code")),
+            _ => panic!("expected in subcommand"),
+        }
+    }
+
+    #[test]
+    fn cli_accepts_ephemeral_flag() {
+        let cli = Cli::try_parse_from(["punch", "out", "--ephemeral", "22"]).unwrap();
+        assert!(cli.identity.ephemeral);
+    }
+
     #[test]
     fn cli_still_accepts_stdio_mapping_after_double_dash() {
         let cli = Cli::try_parse_from(["punch", "in", "peer", "--", "-:22"]).unwrap();
-        match cli {
-            Cli::In { mappings, .. } => assert_eq!(mappings, vec!["-:22"]),
+        match cli.command {
+            Command::In { mappings, .. } => assert_eq!(mappings, vec!["-:22"]),
             _ => panic!("expected in subcommand"),
         }
     }
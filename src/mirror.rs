@@ -0,0 +1,148 @@
+use crate::control::Offer;
+use crate::parse::{LocalTarget, Mapping, PortRange, Protocol, Remote, Target};
+use std::collections::{HashMap, HashSet};
+
+/// How `punch in --all` picks the local port of each remote port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalPorts {
+    /// The remote port plus an offset, e.g. `8080` on `18080` for `--offset 10000`.
+    Offset(u16),
+    /// The lowest free port in a range, in the order the remote peer lists its ports.
+    Range(PortRange),
+}
+
+/// The mappings for an offer, and the offered ports that got none.
+pub struct Mirrored {
+    pub mappings: Vec<Mapping>,
+    /// `(protocol, remote port)` of each offered port no local port was left for.
+    pub unmapped: Vec<(Protocol, u16)>,
+}
+
+/// Turns the offers of a `punch out` peer into mappings for every port it offers.
+pub struct Mirror {
+    local: LocalPorts,
+    /// The local port of each mirrored `(protocol, remote port)`.
+    assigned: HashMap<(Protocol, u16), u16>,
+}
+
+impl Mirror {
+    pub fn new(local: LocalPorts) -> Self {
+        Self {
+            local,
+            assigned: HashMap::new(),
+        }
+    }
+
+    /// Maps every port `offer` lists on the remote peer's `127.0.0.1`. Ports that stay
+    /// offered keep their local port, so their listeners do not move. Services and ports
+    /// on other hosts are not mirrored.
+    pub fn mappings(&mut self, offer: &Offer) -> Mirrored {
+        let remote: Vec<(Protocol, u16)> = offer
+            .ports
+            .iter()
+            .filter_map(|spec| match &spec.target {
+                Target::Inet { host: None, ports } => {
+                    Some(ports.ports().map(|port| (spec.protocol, port)))
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        let offered: HashSet<_> = remote.iter().copied().collect();
+        self.assigned.retain(|key, _| offered.contains(key));
+        let mut used: HashSet<(Protocol, u16)> = self
+            .assigned
+            .iter()
+            .map(|(&(protocol, _), &local)| (protocol, local))
+            .collect();
+
+        let mut mappings = Vec::with_capacity(remote.len());
+        let mut unmapped = Vec::new();
+        for (protocol, port) in remote {
+            let local = match self.assigned.get(&(protocol, port)) {
+                Some(&local) => Some(local),
+                None => match self.local {
+                    LocalPorts::Offset(offset) => port.checked_add(offset),
+                    LocalPorts::Range(range) => range
+                        .ports()
+                        .find(|local| !used.contains(&(protocol, *local))),
+                },
+            };
+            let Some(local) = local else {
+                unmapped.push((protocol, port));
+                continue;
+            };
+            self.assigned.insert((protocol, port), local);
+            used.insert((protocol, local));
+            mappings.push(Mapping {
+                local: LocalTarget::loopback(local),
+                remote: Remote::Target(Target::loopback(port)),
+                protocol,
+            });
+        }
+        Mirrored { mappings, unmapped }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(entries: &str) -> Offer {
+        Offer::decode(entries.as_bytes()).unwrap()
+    }
+
+    fn mapped(mirrored: &Mirrored) -> Vec<String> {
+        mirrored
+            .mappings
+            .iter()
+            .map(|mapping| {
+                let LocalTarget::Addr(local) = mapping.local else {
+                    panic!("expected a local port");
+                };
+                format!(
+                    "{}:{}/{}",
+                    local.port(),
+                    mapping.remote,
+                    mapping.protocol.suffix()
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn offsets_shift_every_loopback_port() {
+        let mut mirror = Mirror::new(LocalPorts::Offset(10000));
+        let offer = offer(
+            "port 22/tcp\nport 5000-5001/udp\nport db.lan:5432/tcp\nport 60000/tcp\nservice ssh\n",
+        );
+        let mirrored = mirror.mappings(&offer);
+        assert_eq!(
+            mapped(&mirrored),
+            ["10022:22/tcp", "15000:5000/udp", "15001:5001/udp"]
+        );
+        assert_eq!(mirrored.unmapped, [(Protocol::Tcp, 60000)]);
+    }
+
+    #[test]
+    fn ranges_keep_assigned_ports_across_changes() {
+        let mut mirror = Mirror::new(LocalPorts::Range("20000-20002".parse().unwrap()));
+        assert_eq!(
+            mapped(&mirror.mappings(&offer("port 22/tcp\nport 8080/tcp\nport 53/udp\n"))),
+            ["20000:22/tcp", "20001:8080/tcp", "20000:53/udp"]
+        );
+        assert_eq!(
+            mapped(&mirror.mappings(&offer("port 80/tcp\nport 8080/tcp\nport 9000/tcp\n"))),
+            ["20000:80/tcp", "20001:8080/tcp", "20002:9000/tcp"]
+        );
+        let mirrored = mirror.mappings(&offer("port 80-83/tcp\nport 8080/tcp\n"));
+        assert_eq!(
+            mapped(&mirrored),
+            ["20000:80/tcp", "20002:81/tcp", "20001:8080/tcp"]
+        );
+        assert_eq!(
+            mirrored.unmapped,
+            [(Protocol::Tcp, 82), (Protocol::Tcp, 83)]
+        );
+    }
+}
@@ -1,5 +1,9 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// A validated port number (1–65535).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -23,6 +27,91 @@ impl FromStr for Port {
     }
 }
 
+/// An inclusive range of ports such as `10000-10100`, or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    pub fn single(port: u16) -> Self {
+        Self {
+            first: port,
+            last: port,
+        }
+    }
+
+    pub fn first(self) -> u16 {
+        self.first
+    }
+
+    pub fn last(self) -> u16 {
+        self.last
+    }
+
+    /// The port, if the range holds exactly one.
+    pub fn single_port(self) -> Option<u16> {
+        (self.first == self.last).then_some(self.first)
+    }
+
+    pub fn count(self) -> u32 {
+        u32::from(self.last - self.first) + 1
+    }
+
+    pub fn contains(self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+
+    /// Joins two ranges that overlap or touch, such as `1-5` and `6-9`.
+    pub fn merge(self, other: PortRange) -> Option<PortRange> {
+        let (low, high) = if self <= other {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        (u32::from(low.last) + 1 >= u32::from(high.first)).then(|| Self {
+            first: low.first,
+            last: low.last.max(high.last),
+        })
+    }
+
+    pub fn intersect(self, other: PortRange) -> Option<PortRange> {
+        let first = self.first.max(other.first);
+        let last = self.last.min(other.last);
+        (first <= last).then_some(Self { first, last })
+    }
+
+    pub fn ports(self) -> impl Iterator<Item = u16> {
+        self.first..=self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((first, last)) = s.split_once('-') else {
+            return Ok(Self::single(s.parse::<Port>()?.get()));
+        };
+        let first = first.parse::<Port>()?.get();
+        let last = last.parse::<Port>()?.get();
+        if first > last {
+            bail!("port range {s} must be ascending");
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.single_port() {
+            Some(port) => write!(f, "{port}"),
+            None => write!(f, "{}-{}", self.first, self.last),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     Tcp,
@@ -50,66 +139,481 @@ impl FromStr for Protocol {
     }
 }
 
-/// A port exposed by `punch out`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct PortSpec {
-    port: Port,
-    pub protocol: Protocol,
+/// The host that targets without one are reached on.
+pub const LOOPBACK: &str = "127.0.0.1";
+
+/// A port on the machine running `punch out`, on another host it can reach, or a Unix
+/// domain socket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Inet {
+        /// A hostname or IP address. `None` is `127.0.0.1`.
+        host: Option<String>,
+        ports: PortRange,
+    },
+    /// Only reachable through a named service.
+    Unix(UnixSocket),
 }
 
-impl PortSpec {
-    pub fn port(self) -> u16 {
-        self.port.get()
+impl Target {
+    pub fn loopback(port: u16) -> Self {
+        Target::Inet {
+            host: None,
+            ports: PortRange::single(port),
+        }
+    }
+
+    /// The `(host, port)` to connect to, or `None` for a Unix socket or a port range.
+    pub fn inet_addr(&self) -> Option<(&str, u16)> {
+        match self {
+            Target::Inet { host, ports } => {
+                Some((host.as_deref().unwrap_or(LOOPBACK), ports.single_port()?))
+            }
+            Target::Unix(_) => None,
+        }
+    }
+
+    /// Whether both targets are on the same host and share a port, or are the same socket.
+    pub fn overlaps(&self, other: &Target) -> bool {
+        match (self, other) {
+            (
+                Target::Inet { host, ports },
+                Target::Inet {
+                    host: other_host,
+                    ports: other_ports,
+                },
+            ) => host == other_host && ports.intersect(*other_ports).is_some(),
+            (Target::Unix(socket), Target::Unix(other)) => socket == other,
+            _ => false,
+        }
+    }
+
+    /// The part of this target that `other` also covers.
+    pub fn intersect(&self, other: &Target) -> Option<Target> {
+        match (self, other) {
+            (
+                Target::Inet { host, ports },
+                Target::Inet {
+                    host: other_host,
+                    ports: other_ports,
+                },
+            ) if host == other_host => Some(Target::Inet {
+                host: host.clone(),
+                ports: ports.intersect(*other_ports)?,
+            }),
+            (Target::Unix(socket), Target::Unix(other)) if socket == other => Some(self.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(socket) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(Target::Unix(socket.parse()?));
+        }
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, port) = rest
+                .split_once("]:")
+                .context("IPv6 targets must be [<address>]:<port>")?;
+            host.parse::<Ipv6Addr>().context("invalid IPv6 address")?;
+            (Some(host.to_string()), port)
+        } else if let Some((host, port)) = s.split_once(':') {
+            (Some(parse_host(host)?), port)
+        } else {
+            (None, s)
+        };
+        Ok(Target::Inet {
+            host,
+            ports: port.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Inet {
+                host: Some(host),
+                ports,
+            } if host.contains(':') => write!(f, "[{host}]:{ports}"),
+            Target::Inet {
+                host: Some(host),
+                ports,
+            } => write!(f, "{host}:{ports}"),
+            Target::Inet { host: None, ports } => write!(f, "{ports}"),
+            Target::Unix(socket) => write!(f, "{UNIX_PREFIX}{socket}"),
+        }
+    }
+}
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// A Unix domain socket: an absolute path, or a Linux abstract socket name written
+/// `@name`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UnixSocket {
+    Path(PathBuf),
+    Abstract(String),
+}
+
+impl FromStr for UnixSocket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(name) = s.strip_prefix('@') {
+            if name.is_empty() {
+                bail!("abstract socket name must not be empty");
+            }
+            return Ok(UnixSocket::Abstract(name.to_string()));
+        }
+        let path = PathBuf::from(s);
+        if !path.is_absolute() {
+            bail!("unix socket path must be absolute, got {s:?}");
+        }
+        Ok(UnixSocket::Path(path))
     }
 }
 
+impl fmt::Display for UnixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnixSocket::Path(path) => write!(f, "{}", path.display()),
+            UnixSocket::Abstract(name) => write!(f, "@{name}"),
+        }
+    }
+}
+
+/// Validates an IPv4 address or hostname. Hostnames are lowercased so targets compare
+/// equal regardless of case.
+pub fn parse_host(host: &str) -> Result<String> {
+    if host.parse::<Ipv4Addr>().is_ok() {
+        return Ok(host.to_string());
+    }
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && !host.starts_with(['-', '.'])
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'));
+    if !valid {
+        bail!("invalid host {host:?}, IPv6 addresses must be in brackets");
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+/// A target exposed by `punch out`, e.g. `22`, `53/udp`, `db.lan:5432` or
+/// `unix:/run/postgresql/.s.PGSQL.5432`. Unix sockets take no protocol suffix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortSpec {
+    pub target: Target,
+    pub protocol: Protocol,
+}
+
 impl FromStr for PortSpec {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let (port, protocol) = split_protocol_suffix(s)?;
-        let port: Port = port.parse()?;
-        Ok(PortSpec { port, protocol })
+        if s.starts_with(UNIX_PREFIX) {
+            return Ok(PortSpec {
+                target: s.parse()?,
+                protocol: Protocol::Tcp,
+            });
+        }
+        let (target, protocol) = split_protocol_suffix(s)?;
+        Ok(PortSpec {
+            target: target.parse()?,
+            protocol,
+        })
     }
 }
 
-/// A local:remote port mapping for `punch in`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+impl PortSpec {
+    /// The ports covered by both specs, e.g. `10050-10100/udp` for `10000-10100/udp` and
+    /// `10050-10200/udp`.
+    pub fn intersect(&self, other: &PortSpec) -> Option<PortSpec> {
+        if self.protocol != other.protocol {
+            return None;
+        }
+        Some(PortSpec {
+            target: self.target.intersect(&other.target)?,
+            protocol: self.protocol,
+        })
+    }
+
+    pub fn overlaps(&self, other: &PortSpec) -> bool {
+        self.protocol == other.protocol && self.target.overlaps(&other.target)
+    }
+}
+
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            Target::Unix(_) => write!(f, "{}", self.target),
+            Target::Inet { .. } => write!(f, "{}/{}", self.target, self.protocol.suffix()),
+        }
+    }
+}
+
+/// Where `punch in` accepts connections for a mapping.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LocalTarget {
-    Port(u16),
+    /// A local port, bound on `127.0.0.1` unless an address is given.
+    Addr(SocketAddr),
+    /// A Unix socket file created by `punch in`.
+    Unix(PathBuf),
     Stdio,
 }
 
-/// A local:remote port mapping for `punch in`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+impl LocalTarget {
+    pub fn loopback(port: u16) -> Self {
+        LocalTarget::Addr(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+}
+
+/// What a mapping reaches on the machine running `punch out`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Remote {
+    Target(Target),
+    /// A service named by `punch out`, resolved to a port by the server.
+    Service(String),
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Remote::Target(target) => write!(f, "{target}"),
+            Remote::Service(name) => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for Remote {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with(UNIX_PREFIX) {
+            bail!("unix sockets can only be reached through a named service");
+        }
+        if s.starts_with(|c: char| c.is_ascii_digit() || c == '[') || s.contains(':') {
+            let target = s.parse().context("invalid remote target")?;
+            return Ok(Remote::Target(target));
+        }
+        validate_service_name(s)?;
+        Ok(Remote::Service(s.to_string()))
+    }
+}
+
+/// A port exposed by `punch out`, optionally under a service name (`ssh=22`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Exposure {
+    pub name: Option<String>,
+    pub port: PortSpec,
+}
+
+impl From<PortSpec> for Exposure {
+    fn from(port: PortSpec) -> Self {
+        Self { name: None, port }
+    }
+}
+
+impl fmt::Display for Exposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name}={}", self.port),
+            None => write!(f, "{}", self.port),
+        }
+    }
+}
+
+impl FromStr for Exposure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, port)) = s.split_once('=') else {
+            if s.starts_with(UNIX_PREFIX) {
+                bail!("unix sockets must be exposed as named services, e.g. docker={s}");
+            }
+            return Ok(Self {
+                name: None,
+                port: s.parse()?,
+            });
+        };
+        validate_service_name(name)?;
+        let port: PortSpec = port.parse()?;
+        if port.protocol == Protocol::Udp {
+            bail!("named services must use tcp");
+        }
+        if let Target::Inet { ports, .. } = &port.target
+            && ports.single_port().is_none()
+        {
+            bail!("named services must be a single port");
+        }
+        Ok(Self {
+            name: Some(name.to_string()),
+            port,
+        })
+    }
+}
+
+/// Service names start with a letter so they cannot be mistaken for port numbers.
+fn validate_service_name(name: &str) -> Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.len() <= 255
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!(
+            "service name must start with a letter and use only letters, digits, '-', '_' and '.', got {name:?}"
+        );
+    }
+    Ok(())
+}
+
+/// A local:remote mapping for `punch in`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Mapping {
     pub local: LocalTarget,
-    pub remote: u16,
+    pub remote: Remote,
     pub protocol: Protocol,
 }
 
 impl FromStr for Mapping {
     type Err = anyhow::Error;
 
+    /// Parses a mapping of a single port. Use [`parse_mapping`] for port ranges.
     fn from_str(s: &str) -> Result<Self> {
-        let (l, r) = s
-            .split_once(':')
-            .context("mapping must be <local>:<remote>")?;
-        let local = match l {
-            "-" => LocalTarget::Stdio,
-            _ => LocalTarget::Port(l.parse::<Port>().context("invalid local port")?.get()),
-        };
-        let (remote, protocol) = split_protocol_suffix(r)?;
-        if local == LocalTarget::Stdio && protocol == Protocol::Udp {
-            bail!("stdio mappings must use tcp");
-        }
-        let remote: Port = remote.parse().context("invalid remote port")?;
-        Ok(Mapping {
-            local,
-            remote: remote.get(),
+        let mut mappings = parse_mapping(s)?;
+        if mappings.len() != 1 {
+            bail!("expected a single port, got the range {s}");
+        }
+        Ok(mappings.remove(0))
+    }
+}
+
+/// Parses a mapping such as `3000:8080`, expanding port ranges such as
+/// `20000-20100:10000-10100/udp` into one mapping per port.
+pub fn parse_mapping(s: &str) -> Result<Vec<Mapping>> {
+    let (local, r) = split_local(s)?;
+    let (remote, protocol) = split_protocol_suffix(r)?;
+    if local == LocalSide::Stdio && protocol == Protocol::Udp {
+        bail!("stdio mappings must use tcp");
+    }
+    if matches!(local, LocalSide::Unix(_)) && protocol == Protocol::Udp {
+        bail!("unix socket mappings must use tcp");
+    }
+    let remote: Remote = remote.parse()?;
+    if matches!(remote, Remote::Service(_)) && protocol == Protocol::Udp {
+        bail!("named services must use tcp");
+    }
+
+    let (ip, local_ports) = match local {
+        LocalSide::Ports(ip, ports) => (ip, ports),
+        LocalSide::Stdio | LocalSide::Unix(_) => {
+            if let Remote::Target(Target::Inet { ports, .. }) = &remote
+                && ports.single_port().is_none()
+            {
+                bail!("port ranges need a local port range, got {s}");
+            }
+            let local = match local {
+                LocalSide::Unix(path) => LocalTarget::Unix(path),
+                _ => LocalTarget::Stdio,
+            };
+            return Ok(vec![Mapping {
+                local,
+                remote,
+                protocol,
+            }]);
+        }
+    };
+    let local_addr = |port| match ip {
+        Some(ip) => LocalTarget::Addr(SocketAddr::new(ip, port)),
+        None => LocalTarget::loopback(port),
+    };
+
+    let (host, remote_ports) = match remote {
+        Remote::Target(Target::Inet { host, ports }) => (host, ports),
+        remote => {
+            let Some(port) = local_ports.single_port() else {
+                bail!("local port range {local_ports} needs a remote port range");
+            };
+            return Ok(vec![Mapping {
+                local: local_addr(port),
+                remote,
+                protocol,
+            }]);
+        }
+    };
+    if local_ports.count() != remote_ports.count() {
+        bail!("port ranges {local_ports} and {remote_ports} must have the same number of ports");
+    }
+    Ok(local_ports
+        .ports()
+        .zip(remote_ports.ports())
+        .map(|(local, port)| Mapping {
+            local: local_addr(local),
+            remote: Remote::Target(Target::Inet {
+                host: host.clone(),
+                ports: PortRange::single(port),
+            }),
             protocol,
         })
+        .collect())
+}
+
+/// The local side of a mapping before port ranges are expanded.
+#[derive(Debug, PartialEq)]
+enum LocalSide {
+    Ports(Option<IpAddr>, PortRange),
+    Unix(PathBuf),
+    Stdio,
+}
+
+/// Splits a mapping into its local side and the rest: `-`, `unix:<path>`, `<ports>`, or
+/// `<address>:<ports>` where an IPv6 address is in brackets.
+fn split_local(s: &str) -> Result<(LocalSide, &str)> {
+    // Socket paths end at the first ':', so they cannot contain one.
+    if let Some(rest) = s.strip_prefix(UNIX_PREFIX) {
+        let (path, r) = rest
+            .split_once(':')
+            .context("mapping must be unix:<path>:<remote>")?;
+        if path.is_empty() {
+            bail!("unix socket path must not be empty");
+        }
+        return Ok((LocalSide::Unix(path.into()), r));
     }
+
+    let (ip, rest) = if let Some(rest) = s.strip_prefix('[') {
+        let (ip, rest) = rest
+            .split_once("]:")
+            .context("IPv6 bind addresses must be [<address>]:<port>")?;
+        let ip: Ipv6Addr = ip.parse().context("invalid IPv6 bind address")?;
+        (Some(IpAddr::from(ip)), rest)
+    } else {
+        // Only an IPv4 address is taken as a bind address, so `3000:db.lan:5432` still
+        // maps a local port to a remote host.
+        let ipv4 = s
+            .split_once(':')
+            .and_then(|(ip, rest)| Some((ip.parse::<Ipv4Addr>().ok()?, rest)));
+        match ipv4 {
+            Some((ip, rest)) => (Some(IpAddr::from(ip)), rest),
+            None => (None, s),
+        }
+    };
+
+    let (l, r) = rest
+        .split_once(':')
+        .context("mapping must be <local>:<remote>")?;
+    let local = match (ip, l) {
+        (None, "-") => LocalSide::Stdio,
+        // Port 0 lets the OS pick a free port, which `punch in` reports once bound.
+        (ip, "0") => LocalSide::Ports(ip, PortRange::single(0)),
+        (ip, _) => LocalSide::Ports(ip, l.parse().context("invalid local port")?),
+    };
+    Ok((local, r))
 }
 
 fn split_protocol_suffix(s: &str) -> Result<(&str, Protocol)> {
@@ -123,30 +627,199 @@ pub fn parse_ports(args: &[String]) -> Result<Vec<PortSpec>> {
     let mut ports = Vec::with_capacity(args.len());
     for arg in args {
         let port: PortSpec = arg.parse()?;
-        if ports.contains(&port) {
-            bail!("duplicate port: {}/{}", port.port(), port.protocol.suffix());
+        if let Some(existing) = ports.iter().find(|existing| port.overlaps(existing)) {
+            return Err(overlap_error(existing, &port));
         }
         ports.push(port);
     }
     Ok(ports)
 }
 
+/// Parses `punch out` arguments such as `22`, `53/udp` or `ssh=22`.
+pub fn parse_exposures(args: &[String]) -> Result<Vec<Exposure>> {
+    let mut exposures: Vec<Exposure> = Vec::with_capacity(args.len());
+    for arg in args {
+        let exposure: Exposure = arg.parse()?;
+        check_exposure(&exposures, &exposure)?;
+        exposures.push(exposure);
+    }
+    Ok(exposures)
+}
+
+/// Rejects an exposure whose port or service name is already taken.
+pub fn check_exposure(exposures: &[Exposure], exposure: &Exposure) -> Result<()> {
+    for existing in exposures {
+        if existing.port.overlaps(&exposure.port) {
+            return Err(overlap_error(&existing.port, &exposure.port));
+        }
+        if let Some(name) = &exposure.name
+            && existing.name.as_ref() == Some(name)
+        {
+            bail!("duplicate service: {name}");
+        }
+    }
+    Ok(())
+}
+
 pub fn parse_mappings(args: &[String]) -> Result<Vec<Mapping>> {
     let mut mappings = Vec::with_capacity(args.len());
-    let mut stdio_count = 0usize;
     for arg in args {
-        let mapping: Mapping = arg.parse()?;
-        if mapping.local == LocalTarget::Stdio {
-            stdio_count += 1;
-            if stdio_count > 1 {
-                bail!("at most one stdio mapping is allowed");
-            }
+        for mapping in parse_mapping(arg)? {
+            check_mapping(&mappings, &mapping)?;
+            mappings.push(mapping);
         }
-        mappings.push(mapping);
     }
     Ok(mappings)
 }
 
+/// A `punch in --reverse` mapping such as `9000:3000`: `punch out` listens on its port
+/// `9000` and forwards each connection back to port `3000` on the machine running
+/// `punch in`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reverse {
+    pub remote: u16,
+    pub local: u16,
+}
+
+impl FromStr for Reverse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (remote, local) = s
+            .split_once(':')
+            .context("reverse mapping must be <remote port>:<local port>")?;
+        Ok(Self {
+            remote: remote.parse::<Port>()?.get(),
+            local: local.parse::<Port>()?.get(),
+        })
+    }
+}
+
+impl fmt::Display for Reverse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.remote, self.local)
+    }
+}
+
+pub fn parse_reverses(args: &[String]) -> Result<Vec<Reverse>> {
+    let mut reverses: Vec<Reverse> = Vec::with_capacity(args.len());
+    for arg in args {
+        let reverse: Reverse = arg
+            .parse()
+            .with_context(|| format!("invalid --reverse {arg}"))?;
+        if reverses.iter().any(|r| r.remote == reverse.remote) {
+            bail!("duplicate reverse port: {}", reverse.remote);
+        }
+        reverses.push(reverse);
+    }
+    Ok(reverses)
+}
+
+fn overlap_error(existing: &PortSpec, port: &PortSpec) -> anyhow::Error {
+    if existing == port {
+        anyhow!("duplicate port: {port}")
+    } else {
+        anyhow!("overlapping ports: {existing} and {port}")
+    }
+}
+
+/// Rejects a mapping whose local port, socket or stdio is already taken.
+pub fn check_mapping(mappings: &[Mapping], mapping: &Mapping) -> Result<()> {
+    for existing in mappings {
+        match (&existing.local, &mapping.local) {
+            (LocalTarget::Stdio, LocalTarget::Stdio) => {
+                bail!("at most one stdio mapping is allowed")
+            }
+            (LocalTarget::Addr(a), LocalTarget::Addr(b))
+                if a == b && a.port() != 0 && existing.protocol == mapping.protocol =>
+            {
+                bail!("duplicate local port: {b}/{}", mapping.protocol.suffix())
+            }
+            (LocalTarget::Unix(a), LocalTarget::Unix(b)) if a == b => {
+                bail!("duplicate unix socket: {}", b.display())
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parses a duration such as `90`, `30s`, `10m`, `1h` or `7d`. Bare numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().context("duration must start with a number")?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("duration unit must be s, m, h or d"),
+    };
+    let secs = value
+        .checked_mul(unit_secs)
+        .context("duration is too long")?;
+    if secs == 0 {
+        bail!("duration must be greater than zero");
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a `YYYY-MM-DD` date into seconds since the Unix epoch at its start, in UTC.
+pub fn parse_date(s: &str) -> Result<u64> {
+    let mut parts = s.splitn(3, '-');
+    let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("date must be YYYY-MM-DD");
+    };
+    let year: i64 = year.parse().context("invalid year")?;
+    let month: u32 = month.parse().context("invalid month")?;
+    let day: u32 = day.parse().context("invalid day")?;
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        bail!("date must be YYYY-MM-DD");
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        bail!("day must be 1–{days_in_month}");
+    }
+
+    // Days from the civil calendar, after Howard Hinnant's `days_from_civil`.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month as i64 + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days as u64 * 24 * 60 * 60)
+}
+
+/// Parses an expiry given as a `YYYY-MM-DD` date, valid through the end of that day in
+/// UTC, or as a duration from `now`. Returns seconds since the Unix epoch.
+pub fn parse_expiry(s: &str, now: SystemTime) -> Result<u64> {
+    if s.contains('-') {
+        return parse_date(s)?
+            .checked_add(24 * 60 * 60)
+            .context("expiry is too far in the future");
+    }
+    let duration = parse_duration(s).context("expiry must be YYYY-MM-DD or a duration")?;
+    let expiry = now
+        .duration_since(UNIX_EPOCH)?
+        .checked_add(duration)
+        .context("expiry is too far in the future")?;
+    Ok(expiry.as_secs())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -176,14 +849,114 @@ mod tests {
     #[test]
     fn port_spec_valid() {
         let spec: PortSpec = "53/udp".parse().unwrap();
-        assert_eq!(spec.port(), 53);
+        assert_eq!(spec.target, Target::loopback(53));
         assert_eq!(spec.protocol, Protocol::Udp);
 
         let spec: PortSpec = "80".parse().unwrap();
-        assert_eq!(spec.port(), 80);
+        assert_eq!(spec.target, Target::loopback(80));
         assert_eq!(spec.protocol, Protocol::Tcp);
     }
 
+    #[test]
+    fn port_spec_with_host() {
+        let spec: PortSpec = "192.168.1.10:80".parse().unwrap();
+        assert_eq!(
+            spec.target,
+            Target::Inet {
+                host: Some("192.168.1.10".into()),
+                ports: PortRange::single(80)
+            }
+        );
+
+        let spec: PortSpec = "[::1]:53/udp".parse().unwrap();
+        assert_eq!(
+            spec.target,
+            Target::Inet {
+                host: Some("::1".into()),
+                ports: PortRange::single(53)
+            }
+        );
+        assert_eq!(spec.protocol, Protocol::Udp);
+        assert_eq!(spec.to_string(), "[::1]:53/udp");
+
+        let spec: PortSpec = "DB.lan:5432".parse().unwrap();
+        assert_eq!(spec.to_string(), "db.lan:5432/tcp");
+        assert_ne!(spec.target, Target::loopback(5432));
+
+        assert!("::1:53".parse::<PortSpec>().is_err());
+        assert!("[db.lan]:53".parse::<PortSpec>().is_err());
+        assert!("db lan:53".parse::<PortSpec>().is_err());
+        assert!("db.lan:0".parse::<PortSpec>().is_err());
+        assert!(":80".parse::<PortSpec>().is_err());
+    }
+
+    #[test]
+    fn port_ranges() {
+        let range: PortRange = "10000-10100".parse().unwrap();
+        assert_eq!(
+            (range.first(), range.last(), range.count()),
+            (10000, 10100, 101)
+        );
+        assert_eq!(range.to_string(), "10000-10100");
+        assert_eq!("22".parse::<PortRange>().unwrap(), PortRange::single(22));
+        assert_eq!("22-22".parse::<PortRange>().unwrap().to_string(), "22");
+
+        assert!("10100-10000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("10-70000".parse::<PortRange>().is_err());
+        assert!("10-".parse::<PortRange>().is_err());
+
+        let spec: PortSpec = "db.lan:10000-10100/udp".parse().unwrap();
+        assert_eq!(spec.to_string(), "db.lan:10000-10100/udp");
+        let other: PortSpec = "db.lan:10050-10200/udp".parse().unwrap();
+        assert_eq!(
+            spec.intersect(&other),
+            Some("db.lan:10050-10100/udp".parse().unwrap())
+        );
+        assert_eq!(spec.intersect(&"10050/udp".parse().unwrap()), None);
+        assert_eq!(spec.intersect(&"db.lan:10050".parse().unwrap()), None);
+
+        assert!("web=8000-8010".parse::<Exposure>().is_err());
+    }
+
+    #[test]
+    fn overlapping_ranges_are_rejected() {
+        let args: Vec<String> = vec!["10000-10100/udp".into(), "10100-10200/udp".into()];
+        assert!(parse_ports(&args).is_err());
+        let args: Vec<String> = vec!["10000-10100/udp".into(), "10000-10100".into()];
+        assert!(parse_ports(&args).is_ok());
+        assert!(parse_exposures(&["8000-8010".into(), "web=8005".into()]).is_err());
+        assert!(parse_exposures(&["8000-8010".into(), "db.lan:8005".into()]).is_ok());
+    }
+
+    #[test]
+    fn mapping_ranges_expand_per_port() {
+        let mappings = parse_mapping("20000-20002:10000-10002/udp").unwrap();
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[2].local, LocalTarget::loopback(20002));
+        assert_eq!(mappings[2].remote, Remote::Target(Target::loopback(10002)));
+        assert_eq!(mappings[2].protocol, Protocol::Udp);
+
+        let mappings = parse_mapping("0.0.0.0:3000-3001:db.lan:5432-5433").unwrap();
+        assert_eq!(
+            mappings[1].local,
+            LocalTarget::Addr("0.0.0.0:3001".parse().unwrap())
+        );
+        assert_eq!(mappings[1].remote.to_string(), "db.lan:5433");
+
+        assert!(parse_mapping("20000-20100:10000-10099").is_err());
+        assert!(parse_mapping("20000-20100:ssh").is_err());
+        assert!(parse_mapping("3000:8000-8010").is_err());
+        assert!(parse_mapping("-:8000-8010").is_err());
+        assert!(parse_mapping("unix:/tmp/a.sock:8000-8010").is_err());
+        assert!("20000-20002:10000-10002".parse::<Mapping>().is_err());
+
+        let args: Vec<String> = vec!["3000-3010:8000-8010".into(), "3005:22".into()];
+        assert!(parse_mappings(&args).is_err());
+        let args: Vec<String> = vec!["3000-3010:8000-8010".into(), "3005:53/udp".into()];
+        assert!(parse_mappings(&args).is_ok());
+    }
+
     #[test]
     fn port_spec_duplicate_detection_is_per_protocol() {
         let args: Vec<String> = vec!["53/tcp".into(), "53/udp".into()];
@@ -196,30 +969,141 @@ mod tests {
     #[test]
     fn mapping_valid() {
         let m: Mapping = "4000:8080".parse().unwrap();
-        assert_eq!(m.local, LocalTarget::Port(4000));
-        assert_eq!(m.remote, 8080);
+        assert_eq!(m.local, LocalTarget::loopback(4000));
+        assert_eq!(m.remote, Remote::Target(Target::loopback(8080)));
         assert_eq!(m.protocol, Protocol::Tcp);
     }
 
     #[test]
     fn mapping_udp_valid() {
         let m: Mapping = "5300:53/udp".parse().unwrap();
-        assert_eq!(m.local, LocalTarget::Port(5300));
-        assert_eq!(m.remote, 53);
+        assert_eq!(m.local, LocalTarget::loopback(5300));
+        assert_eq!(m.remote, Remote::Target(Target::loopback(53)));
         assert_eq!(m.protocol, Protocol::Udp);
     }
 
+    #[test]
+    fn mapping_ephemeral_local_port() {
+        let m: Mapping = "0:8080".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::loopback(0));
+        let m: Mapping = "[::]:0:53/udp".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Addr("[::]:0".parse().unwrap()));
+
+        let args: Vec<String> = vec!["0:8080".into(), "0:8081".into()];
+        assert_eq!(parse_mappings(&args).unwrap().len(), 2);
+        assert!("3000:0".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn reverse_mappings() {
+        let reverse: Reverse = "9000:3000".parse().unwrap();
+        assert_eq!(
+            reverse,
+            Reverse {
+                remote: 9000,
+                local: 3000
+            }
+        );
+        assert_eq!(reverse.to_string(), "9000:3000");
+        assert!("9000".parse::<Reverse>().is_err());
+        assert!("0:3000".parse::<Reverse>().is_err());
+        assert!("9000:localhost:3000".parse::<Reverse>().is_err());
+
+        let args: Vec<String> = vec!["9000:3000".into(), "9001:3000".into()];
+        assert_eq!(parse_reverses(&args).unwrap().len(), 2);
+        let args: Vec<String> = vec!["9000:3000".into(), "9000:3001".into()];
+        let err = parse_reverses(&args).unwrap_err();
+        assert!(err.to_string().contains("duplicate reverse port"), "{err}");
+    }
+
     #[test]
     fn mapping_stdio_valid() {
         let m: Mapping = "-:22".parse().unwrap();
         assert_eq!(m.local, LocalTarget::Stdio);
-        assert_eq!(m.remote, 22);
+        assert_eq!(m.remote, Remote::Target(Target::loopback(22)));
         assert_eq!(m.protocol, Protocol::Tcp);
     }
 
+    #[test]
+    fn unix_sockets_are_named_services_only() {
+        let exposure: Exposure = "docker=unix:/var/run/docker.sock".parse().unwrap();
+        assert_eq!(
+            exposure.port.target,
+            Target::Unix(UnixSocket::Path("/var/run/docker.sock".into()))
+        );
+        assert_eq!(exposure.to_string(), "docker=unix:/var/run/docker.sock");
+
+        let exposure: Exposure = "agent=unix:@ssh-agent".parse().unwrap();
+        assert_eq!(
+            exposure.port.target,
+            Target::Unix(UnixSocket::Abstract("ssh-agent".into()))
+        );
+
+        let spec: PortSpec = "unix:/run/postgresql/.s.PGSQL.5432".parse().unwrap();
+        assert_eq!(spec.to_string(), "unix:/run/postgresql/.s.PGSQL.5432");
+
+        assert!("unix:/var/run/docker.sock".parse::<Exposure>().is_err());
+        assert!("docker=unix:docker.sock".parse::<Exposure>().is_err());
+        assert!("docker=unix:@".parse::<Exposure>().is_err());
+        assert!("2375:unix:/var/run/docker.sock".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn mapping_with_bind_address() {
+        let m: Mapping = "0.0.0.0:3000:8080".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Addr("0.0.0.0:3000".parse().unwrap()));
+        assert_eq!(m.remote, Remote::Target(Target::loopback(8080)));
+
+        let m: Mapping = "[::1]:3000:8080".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Addr("[::1]:3000".parse().unwrap()));
+
+        let m: Mapping = "[::]:5300:53/udp".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Addr("[::]:5300".parse().unwrap()));
+        assert_eq!(m.protocol, Protocol::Udp);
+
+        let m: Mapping = "0.0.0.0:5432:db.lan:5432".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Addr("0.0.0.0:5432".parse().unwrap()));
+        assert_eq!(m.remote.to_string(), "db.lan:5432");
+
+        let m: Mapping = "3000:10.0.0.2:80".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::loopback(3000));
+        assert_eq!(m.remote.to_string(), "10.0.0.2:80");
+
+        assert!("[::1:3000:8080".parse::<Mapping>().is_err());
+        assert!("[db.lan]:3000:8080".parse::<Mapping>().is_err());
+        assert!("0.0.0.0:-:22".parse::<Mapping>().is_err());
+        assert!("0.0.0.0:3000".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn mapping_from_unix_socket() {
+        let m: Mapping = "unix:/tmp/db.sock:5432".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Unix("/tmp/db.sock".into()));
+        assert_eq!(m.remote, Remote::Target(Target::loopback(5432)));
+
+        let m: Mapping = "unix:db.sock:db.lan:5432".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::Unix("db.sock".into()));
+        assert_eq!(m.remote.to_string(), "db.lan:5432");
+
+        assert!("unix:/tmp/dns.sock:53/udp".parse::<Mapping>().is_err());
+        assert!("unix::5432".parse::<Mapping>().is_err());
+        assert!("unix:/tmp/db.sock".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn mapping_to_host() {
+        let m: Mapping = "5432:db.lan:5432".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::loopback(5432));
+        assert_eq!(m.remote, Remote::Target("db.lan:5432".parse().unwrap()));
+
+        let m: Mapping = "5300:[::1]:53/udp".parse().unwrap();
+        assert_eq!(m.remote.to_string(), "[::1]:53");
+        assert_eq!(m.protocol, Protocol::Udp);
+    }
+
     #[test]
     fn mapping_invalid() {
-        assert!("0:80".parse::<Mapping>().is_err());
+        assert!("0-10:80-90".parse::<Mapping>().is_err());
         assert!("80".parse::<Mapping>().is_err());
         assert!("abc:80".parse::<Mapping>().is_err());
         assert!("80:0".parse::<Mapping>().is_err());
@@ -239,4 +1123,90 @@ mod tests {
         let mappings = parse_mappings(&args).unwrap();
         assert_eq!(mappings.len(), 3);
     }
+
+    #[test]
+    fn duration_valid() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn duration_invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        let err = parse_duration("999999999999999d").unwrap_err();
+        assert_eq!(err.to_string(), "duration is too long");
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn port_spec_display_round_trips() {
+        let spec: PortSpec = "53/udp".parse().unwrap();
+        assert_eq!(spec.to_string(), "53/udp");
+        assert_eq!("22".parse::<PortSpec>().unwrap().to_string(), "22/tcp");
+    }
+
+    #[test]
+    fn date_valid() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 951_868_800);
+        assert_eq!(parse_date("2026-12-31").unwrap(), 1_798_675_200);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 1_709_164_800);
+    }
+
+    #[test]
+    fn date_invalid() {
+        assert!(parse_date("2026-13-01").is_err());
+        assert!(parse_date("2025-02-29").is_err());
+        assert!(parse_date("2026-12").is_err());
+        assert!(parse_date("1969-12-31").is_err());
+        assert!(parse_date("tomorrow").is_err());
+    }
+
+    #[test]
+    fn expiry_from_date_or_duration() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        assert_eq!(parse_expiry("1970-01-01", now).unwrap(), 86_400);
+        assert_eq!(parse_expiry("30d", now).unwrap(), 1_000 + 30 * 86_400);
+        assert!(parse_expiry("soon", now).is_err());
+        let err = parse_expiry("18446744073709551615s", now).unwrap_err();
+        assert_eq!(err.to_string(), "expiry is too far in the future");
+    }
+
+    #[test]
+    fn mapping_to_named_service() {
+        let m: Mapping = "2222:ssh".parse().unwrap();
+        assert_eq!(m.local, LocalTarget::loopback(2222));
+        assert_eq!(m.remote, Remote::Service("ssh".into()));
+        assert_eq!(m.protocol, Protocol::Tcp);
+
+        let m: Mapping = "-:web-1".parse().unwrap();
+        assert_eq!(m.remote, Remote::Service("web-1".into()));
+
+        assert!("5300:dns/udp".parse::<Mapping>().is_err());
+        assert!("2222:-ssh".parse::<Mapping>().is_err());
+        assert!("2222:22x".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn exposures_with_and_without_names() {
+        let args: Vec<String> = vec!["ssh=22".into(), "8080".into(), "53/udp".into()];
+        let exposures = parse_exposures(&args).unwrap();
+        assert_eq!(exposures[0].name.as_deref(), Some("ssh"));
+        assert_eq!(exposures[0].port, "22".parse().unwrap());
+        assert_eq!(exposures[1].name, None);
+        assert_eq!(exposures[0].to_string(), "ssh=22/tcp");
+
+        assert!(parse_exposures(&["ssh=22".into(), "ssh=2222".into()]).is_err());
+        assert!(parse_exposures(&["ssh=22".into(), "22".into()]).is_err());
+        assert!(parse_exposures(&["dns=53/udp".into()]).is_err());
+        assert!(parse_exposures(&["22=22".into()]).is_err());
+        assert!(parse_exposures(&["ssh=0".into()]).is_err());
+    }
 }
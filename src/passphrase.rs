@@ -0,0 +1,126 @@
+use anyhow::{Context, Result, bail};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use zeroize::Zeroizing;
+
+pub const PASSPHRASE_ENV: &str = "PUNCH_KEY_PASSPHRASE";
+
+/// Reads the passphrase for an existing encrypted key from `PUNCH_KEY_PASSPHRASE` or the
+/// terminal.
+pub fn read(prompt: &str) -> Result<Zeroizing<String>> {
+    if let Some(passphrase) = from_env()? {
+        return Ok(passphrase);
+    }
+    prompt_tty(prompt)
+}
+
+/// Reads a passphrase for a newly encrypted key, asking twice on the terminal.
+pub fn read_new() -> Result<Zeroizing<String>> {
+    if let Some(passphrase) = from_env()? {
+        return Ok(passphrase);
+    }
+
+    let passphrase = prompt_tty("new key passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("passphrase must not be empty");
+    }
+    let confirm = prompt_tty("repeat passphrase: ")?;
+    if passphrase != confirm {
+        bail!("passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+fn from_env() -> Result<Option<Zeroizing<String>>> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) if passphrase.is_empty() => bail!("{PASSPHRASE_ENV} must not be empty"),
+        Ok(passphrase) => Ok(Some(Zeroizing::new(passphrase))),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("invalid {PASSPHRASE_ENV}")),
+    }
+}
+
+fn prompt_tty(prompt: &str) -> Result<Zeroizing<String>> {
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .with_context(|| {
+            format!("no terminal to read the passphrase from, set {PASSPHRASE_ENV}")
+        })?;
+
+    let mut output = &tty;
+    output.write_all(prompt.as_bytes())?;
+    output.flush()?;
+
+    let line = {
+        let _echo_guard = NoEchoGuard::new(&tty)?;
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(&tty)
+            .read_line(&mut line)
+            .context("failed to read passphrase")?;
+        line
+    };
+    output.write_all(b"\n")?;
+
+    Ok(Zeroizing::new(
+        line.trim_end_matches(['\r', '\n']).to_string(),
+    ))
+}
+
+/// Turns off terminal echo until dropped.
+struct NoEchoGuard<'a> {
+    tty: &'a File,
+    original: termios::Termios,
+}
+
+impl<'a> NoEchoGuard<'a> {
+    fn new(tty: &'a File) -> Result<Self> {
+        let original = termios::tcgetattr(tty)?;
+        let mut no_echo = original.clone();
+        no_echo.local_flags.remove(LocalFlags::ECHO);
+        termios::tcsetattr(tty, SetArg::TCSANOW, &no_echo)?;
+        Ok(Self { tty, original })
+    }
+}
+
+impl Drop for NoEchoGuard<'_> {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.tty, SetArg::TCSANOW, &self.original);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoEchoGuard;
+    use anyhow::Result;
+    use nix::pty::openpty;
+    use nix::sys::termios::{self, LocalFlags};
+    use std::fs::File;
+
+    #[test]
+    fn echo_is_disabled_and_restored() -> Result<()> {
+        let pty = openpty(None, None)?;
+        let tty = File::from(pty.slave);
+        assert!(
+            termios::tcgetattr(&tty)?
+                .local_flags
+                .contains(LocalFlags::ECHO)
+        );
+
+        {
+            let _guard = NoEchoGuard::new(&tty)?;
+            let flags = termios::tcgetattr(&tty)?.local_flags;
+            assert!(!flags.contains(LocalFlags::ECHO));
+            assert!(flags.contains(LocalFlags::ICANON));
+        }
+
+        assert!(
+            termios::tcgetattr(&tty)?
+                .local_flags
+                .contains(LocalFlags::ECHO)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Returns punch's data directory: `$XDG_DATA_HOME/punch`, falling back to
+/// `~/.local/share/punch` on every platform.
+pub fn data_dir() -> Result<PathBuf> {
+    data_dir_from(std::env::var_os("XDG_DATA_HOME"), dirs::home_dir())
+}
+
+/// Returns punch's config directory: `$XDG_CONFIG_HOME/punch`, falling back to
+/// `~/.config/punch` on every platform.
+pub fn config_dir() -> Result<PathBuf> {
+    config_dir_from(std::env::var_os("XDG_CONFIG_HOME"), dirs::home_dir())
+}
+
+fn data_dir_from(xdg_data_home: Option<OsString>, home: Option<PathBuf>) -> Result<PathBuf> {
+    xdg_dir(xdg_data_home, home, ".local/share")
+}
+
+fn config_dir_from(xdg_config_home: Option<OsString>, home: Option<PathBuf>) -> Result<PathBuf> {
+    xdg_dir(xdg_config_home, home, ".config")
+}
+
+fn xdg_dir(xdg_home: Option<OsString>, home: Option<PathBuf>, fallback: &str) -> Result<PathBuf> {
+    // The XDG spec says relative paths must be ignored.
+    if let Some(dir) = xdg_home.map(PathBuf::from).filter(|dir| dir.is_absolute()) {
+        return Ok(dir.join("punch"));
+    }
+    let home = home.context("cannot determine home directory")?;
+    Ok(home.join(fallback).join("punch"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_dir_prefers_absolute_xdg_data_home() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(
+            data_dir_from(Some("/data".into()), home.clone()).unwrap(),
+            PathBuf::from("/data/punch")
+        );
+        assert_eq!(
+            data_dir_from(Some("relative".into()), home.clone()).unwrap(),
+            PathBuf::from("/home/me/.local/share/punch")
+        );
+        assert_eq!(
+            data_dir_from(None, home).unwrap(),
+            PathBuf::from("/home/me/.local/share/punch")
+        );
+        assert!(data_dir_from(None, None).is_err());
+    }
+
+    #[test]
+    fn config_dir_prefers_absolute_xdg_config_home() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(
+            config_dir_from(Some("/cfg".into()), home.clone()).unwrap(),
+            PathBuf::from("/cfg/punch")
+        );
+        assert_eq!(
+            config_dir_from(None, home).unwrap(),
+            PathBuf::from("/home/me/.config/punch")
+        );
+    }
+}
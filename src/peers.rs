@@ -0,0 +1,280 @@
+use crate::parse;
+use crate::paths;
+use anyhow::{Context, Result, bail};
+use iroh::EndpointId;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const KNOWN_PEERS_FILE: &str = "known_peers";
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join(KNOWN_PEERS_FILE))
+}
+
+/// A remote peer saved under a friendly name for `punch in`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownPeer {
+    pub name: String,
+    pub id: EndpointId,
+    /// Mappings used when `punch in` is given none.
+    pub mappings: Vec<String>,
+}
+
+impl KnownPeer {
+    pub fn new(name: &str, id: EndpointId, mappings: Vec<String>) -> Result<Self> {
+        validate_name(name)?;
+        parse::parse_mappings(&mappings)?;
+        Ok(Self {
+            name: name.to_string(),
+            id,
+            mappings,
+        })
+    }
+
+    fn line(&self) -> String {
+        let mut line = format!("{} {}", self.name, self.id);
+        for mapping in &self.mappings {
+            line.push(' ');
+            line.push_str(mapping);
+        }
+        line
+    }
+}
+
+/// The known peers file: one `<name> <endpoint-id> [mapping...]` entry per line. `#`
+/// starts a comment.
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    path: PathBuf,
+    /// Raw lines, kept so comments survive edits.
+    lines: Vec<String>,
+    peers: Vec<(usize, KnownPeer)>,
+}
+
+impl KnownPeers {
+    /// Loads the file at `path`. A missing file has no peers.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to read known peers file {}", path.display())
+                });
+            }
+        };
+        Self::parse(path, &contents)
+            .with_context(|| format!("invalid known peers file {}", path.display()))
+    }
+
+    fn parse(path: &Path, contents: &str) -> Result<Self> {
+        let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+        let mut peers: Vec<(usize, KnownPeer)> = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            let tokens: Vec<&str> = strip_comment(line).split_whitespace().collect();
+            let [name, id, mappings @ ..] = tokens.as_slice() else {
+                if tokens.is_empty() {
+                    continue;
+                }
+                bail!(
+                    "line {}: expected <name> <endpoint-id> [mapping...]",
+                    index + 1
+                );
+            };
+            let id: EndpointId = id
+                .parse()
+                .with_context(|| format!("line {}: invalid endpoint ID", index + 1))?;
+            let mappings = mappings.iter().map(|m| m.to_string()).collect();
+            let peer = KnownPeer::new(name, id, mappings)
+                .with_context(|| format!("line {}", index + 1))?;
+            if peers.iter().any(|(_, existing)| existing.name == peer.name) {
+                bail!("line {}: duplicate peer {name}", index + 1);
+            }
+            peers.push((index, peer));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines,
+            peers,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KnownPeer> {
+        self.iter().find(|peer| peer.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KnownPeer> {
+        self.peers.iter().map(|(_, peer)| peer)
+    }
+
+    /// Adds `peer`, replacing an entry with the same name only if `force` is set.
+    pub fn add(&mut self, peer: KnownPeer, force: bool) -> Result<()> {
+        match self.peers.iter_mut().find(|(_, p)| p.name == peer.name) {
+            Some(_) if !force => bail!(
+                "peer {} already exists, use --force to replace it",
+                peer.name
+            ),
+            Some((line, existing)) => {
+                self.lines[*line] = peer.line();
+                *existing = peer;
+            }
+            None => {
+                self.lines.push(peer.line());
+                self.peers.push((self.lines.len() - 1, peer));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<KnownPeer> {
+        let index = self
+            .peers
+            .iter()
+            .position(|(_, peer)| peer.name == name)
+            .with_context(|| format!("no known peer named {name}"))?;
+        let (line, peer) = self.peers.remove(index);
+        self.lines.remove(line);
+        for (other, _) in &mut self.peers {
+            if *other > line {
+                *other -= 1;
+            }
+        }
+        Ok(peer)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("failed to create known peers directory")?;
+        }
+        let mut contents = self.lines.join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        // Renaming a complete file means a failed write never truncates the old one.
+        let context = || format!("failed to write known peers file {}", self.path.display());
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, contents).with_context(context)?;
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            fs::set_permissions(&tmp, metadata.permissions()).with_context(context)?;
+        }
+        fs::rename(&tmp, &self.path).with_context(context)
+    }
+}
+
+/// Resolves `peer` given to `punch in`: an endpoint ID, or the name of a known peer.
+pub fn resolve(peer: &str, known: &KnownPeers) -> Result<(EndpointId, Vec<String>)> {
+    if let Ok(id) = peer.parse::<EndpointId>() {
+        return Ok((id, Vec::new()));
+    }
+    match known.get(peer) {
+        Some(known) => Ok((known.id, known.mappings.clone())),
+        None => bail!("{peer:?} is neither an endpoint ID nor a known peer"),
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with(['.', '-'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("peer name must use only letters, digits, '-', '_' and '.', got {name:?}");
+    }
+    if name.parse::<EndpointId>().is_ok() {
+        bail!("peer name must not be an endpoint ID");
+    }
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.split_once('#') {
+        Some((line, _)) => line.trim(),
+        None => line.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn id() -> EndpointId {
+        SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!("punch-known-peers-{}", id()))
+    }
+
+    #[test]
+    fn parse_reads_names_ids_and_mappings() {
+        let (db, web) = (id(), id());
+        let contents = format!("# servers\nprod-db {db} 5432:5432\nweb {web}  # staging\n");
+        let known = KnownPeers::parse(&path(), &contents).unwrap();
+
+        let peer = known.get("prod-db").unwrap();
+        assert_eq!(peer.id, db);
+        assert_eq!(peer.mappings, vec!["5432:5432"]);
+        assert_eq!(known.get("web").unwrap().mappings, Vec::<String>::new());
+        assert!(known.get("staging").is_none());
+    }
+
+    #[test]
+    fn parse_rejects_bad_entries() {
+        let path = path();
+        assert!(KnownPeers::parse(&path, "lonely\n").is_err());
+        assert!(KnownPeers::parse(&path, "db not-a-key\n").is_err());
+        assert!(KnownPeers::parse(&path, &format!("db {} 5432\n", id())).is_err());
+        let err = KnownPeers::parse(&path, &format!("db {}\ndb {}\n", id(), id())).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn add_and_remove_keep_comments() {
+        let path = path();
+        let (a, b) = (id(), id());
+        fs::write(&path, format!("# mine\na {a}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut known = KnownPeers::load(&path).unwrap();
+        let peer = KnownPeer::new("b", b, vec!["3000:80".into()]).unwrap();
+        known.add(peer.clone(), false).unwrap();
+        assert!(known.add(peer, false).is_err());
+        known.remove("a").unwrap();
+        known
+            .add(KnownPeer::new("b", b, vec![]).unwrap(), true)
+            .unwrap();
+        known.save().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, format!("# mine\nb {b}\n"));
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+        assert!(known.remove("a").is_err());
+    }
+
+    #[test]
+    fn resolve_accepts_ids_and_names() {
+        let (a, b) = (id(), id());
+        let known = KnownPeers::parse(&path(), &format!("db {a} 5432:5432\n")).unwrap();
+
+        assert_eq!(resolve(&b.to_string(), &known).unwrap(), (b, vec![]));
+        assert_eq!(
+            resolve("db", &known).unwrap(),
+            (a, vec!["5432:5432".to_string()])
+        );
+        assert!(resolve("web", &known).is_err());
+    }
+
+    #[test]
+    fn names_must_not_look_like_ids() {
+        assert!(KnownPeer::new(&id().to_string(), id(), vec![]).is_err());
+        assert!(KnownPeer::new("-v", id(), vec![]).is_err());
+        assert!(KnownPeer::new("prod.db_1", id(), vec![]).is_ok());
+    }
+}
@@ -0,0 +1,531 @@
+use crate::authorized::{Access, AuthorizedPeers};
+use crate::control::Offer;
+use crate::dest::DestRule;
+use crate::grant::Grant;
+use crate::parse::{Exposure, PortRange, PortSpec, Protocol, Remote, Target};
+use anyhow::{Context, Result, bail};
+use iroh::EndpointId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A set of targets kept as sorted, merged port ranges per host, so exposing
+/// `10000-10100/udp` costs one entry rather than a hundred.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TargetSet {
+    hosts: HashMap<Option<String>, Vec<PortRange>>,
+}
+
+impl TargetSet {
+    /// Whether every port of `target` is in the set.
+    pub(crate) fn contains(&self, target: &Target) -> bool {
+        let Target::Inet { host, ports } = target else {
+            return false;
+        };
+        let Some(ranges) = self.hosts.get(host) else {
+            return false;
+        };
+        let index = ranges.partition_point(|range| range.last() < ports.first());
+        ranges
+            .get(index)
+            .is_some_and(|range| range.contains(ports.first()) && range.contains(ports.last()))
+    }
+
+    /// Every range in the set, ordered by host and then port.
+    fn targets(&self) -> Vec<Target> {
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by_key(|(host, _)| *host);
+        hosts
+            .into_iter()
+            .flat_map(|(host, ranges)| {
+                ranges.iter().map(|&ports| Target::Inet {
+                    host: host.clone(),
+                    ports,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+impl FromIterator<Target> for TargetSet {
+    /// Unix sockets are skipped, since they are only reachable by service name.
+    fn from_iter<I: IntoIterator<Item = Target>>(targets: I) -> Self {
+        let mut hosts: HashMap<_, Vec<PortRange>> = HashMap::new();
+        for target in targets {
+            if let Target::Inet { host, ports } = target {
+                hosts.entry(host).or_default().push(ports);
+            }
+        }
+        for ranges in hosts.values_mut() {
+            ranges.sort();
+            let mut merged: Vec<PortRange> = Vec::with_capacity(ranges.len());
+            for &range in ranges.iter() {
+                match merged.last_mut() {
+                    Some(last) if let Some(joined) = last.merge(range) => *last = joined,
+                    _ => merged.push(range),
+                }
+            }
+            *ranges = merged;
+        }
+        Self { hosts }
+    }
+}
+
+/// The targets a single connection may reach.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AllowedPorts {
+    pub(crate) tcp: Arc<TargetSet>,
+    pub(crate) udp: Arc<TargetSet>,
+    /// Named services whose target is allowed, including Unix sockets.
+    pub(crate) services: Arc<HashMap<String, Target>>,
+    /// Destinations the peer may choose itself, checked when a target is not exposed.
+    pub(crate) dest: Arc<Vec<DestRule>>,
+    /// Ports the peer may ask `punch out` to listen on for `--reverse`.
+    pub(crate) bind: Arc<Vec<PortRange>>,
+}
+
+impl AllowedPorts {
+    fn from_exposures(exposures: impl IntoIterator<Item = Exposure>) -> Self {
+        let mut tcp = Vec::new();
+        let mut udp = Vec::new();
+        let mut services = HashMap::new();
+        for Exposure { name, port } in exposures {
+            if let Some(name) = name {
+                services.insert(name, port.target.clone());
+            }
+            match port.protocol {
+                Protocol::Tcp => tcp.push(port.target),
+                Protocol::Udp => udp.push(port.target),
+            }
+        }
+
+        Self {
+            tcp: Arc::new(tcp.into_iter().collect()),
+            udp: Arc::new(udp.into_iter().collect()),
+            services: Arc::new(services),
+            dest: Arc::default(),
+            bind: Arc::default(),
+        }
+    }
+
+    /// Whether the peer may ask to listen on `port`.
+    pub(crate) fn may_bind(&self, port: u16) -> bool {
+        self.bind.iter().any(|ports| ports.contains(port))
+    }
+
+    /// What the connection may reach, as listed to the peer.
+    pub(crate) fn offer(&self) -> Offer {
+        let ports = |targets: &TargetSet, protocol| {
+            targets
+                .targets()
+                .into_iter()
+                .map(move |target| PortSpec { target, protocol })
+        };
+        let mut services: Vec<String> = self.services.keys().cloned().collect();
+        services.sort();
+        Offer {
+            ports: ports(&self.tcp, Protocol::Tcp)
+                .chain(ports(&self.udp, Protocol::Udp))
+                .collect(),
+            services,
+            dest: self.dest.to_vec(),
+            bind: self.bind.to_vec(),
+        }
+    }
+
+    /// Resolves the target a stream to `remote` should reach, if it is allowed.
+    pub(crate) fn tcp_target(&self, remote: &Remote) -> Option<Target> {
+        match remote {
+            Remote::Target(target) => self.tcp.contains(target).then(|| target.clone()),
+            Remote::Service(name) => self.services.get(name).cloned(),
+        }
+    }
+}
+
+/// The ports exposed by `punch out` and which peers may use each of them.
+#[derive(Clone, Debug)]
+pub(crate) struct Policy {
+    exposed: Vec<Exposure>,
+    /// Destinations `--allow-dest` lets peers with access to every port choose.
+    dest_rules: Vec<DestRule>,
+    /// Ports `--allow-bind` lets peers with access to every port listen on.
+    bind_ports: Vec<PortRange>,
+    authorized: AuthorizedPeers,
+    /// The identity whose signed grants are honoured, normally the `punch out` endpoint.
+    grant_issuer: Option<EndpointId>,
+}
+
+impl Policy {
+    pub(crate) fn new(
+        exposed: impl IntoIterator<Item = impl Into<Exposure>>,
+        authorized: AuthorizedPeers,
+    ) -> Self {
+        Self {
+            exposed: exposed.into_iter().map(Into::into).collect(),
+            dest_rules: Vec::new(),
+            bind_ports: Vec::new(),
+            authorized,
+            grant_issuer: None,
+        }
+    }
+
+    /// Honours grants signed by `issuer`.
+    pub(crate) fn with_grant_issuer(mut self, issuer: EndpointId) -> Self {
+        self.grant_issuer = Some(issuer);
+        self
+    }
+
+    pub(crate) fn with_dest_rules(mut self, rules: Vec<DestRule>) -> Self {
+        self.dest_rules = rules;
+        self
+    }
+
+    pub(crate) fn with_bind_ports(mut self, ports: Vec<PortRange>) -> Self {
+        self.bind_ports = ports;
+        self
+    }
+
+    /// Rejects destination rules and bindable ports when every peer may connect, since
+    /// any peer could then reach the network behind this machine or listen on it.
+    pub(crate) fn check_open_rules(&self) -> Result<()> {
+        if self.authorized.accepts_any()
+            && !(self.dest_rules.is_empty() && self.bind_ports.is_empty())
+        {
+            bail!(
+                "--allow-dest and --allow-bind need peers to be restricted with an \
+                 authorized keys file or --allow"
+            );
+        }
+        Ok(())
+    }
+
+    /// Exposes `ports` to every peer.
+    #[cfg(test)]
+    pub(crate) fn open(ports: &[PortSpec]) -> Self {
+        Self::new(ports.iter().cloned(), AuthorizedPeers::any())
+    }
+
+    /// Lists the exposed ports, allowed destinations and ports peers may listen on, e.g.
+    /// `ssh=22/tcp 53/udp bind:9000-9100`.
+    pub(crate) fn describe_exposed(&self) -> String {
+        let exposed = self.exposed.iter().map(ToString::to_string);
+        let dest = self.dest_rules.iter().map(|rule| format!("dest:{rule}"));
+        let bind = self.bind_ports.iter().map(|ports| format!("bind:{ports}"));
+        exposed
+            .chain(dest)
+            .chain(bind)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub(crate) fn authorized(&self) -> &AuthorizedPeers {
+        &self.authorized
+    }
+
+    /// Lets `peer` connect and reach every exposed port.
+    pub(crate) fn authorize(&mut self, peer: EndpointId) {
+        self.authorized.grant(peer, Access::All);
+    }
+
+    pub(crate) fn accepts(&self, peer: &EndpointId) -> bool {
+        self.authorized.allows(peer)
+    }
+
+    /// Decodes the grant presented by `peer` and checks it was issued to it by a trusted
+    /// issuer and has not expired.
+    pub(crate) fn verify_grant(&self, token: &str, peer: &EndpointId) -> Result<Grant> {
+        let issuer = self
+            .grant_issuer
+            .as_ref()
+            .context("grants are not accepted")?;
+        let grant = Grant::decode(token)?;
+        grant.check(issuer, peer, SystemTime::now())?;
+        Ok(grant)
+    }
+
+    /// Resolves the exposed ports `peer` may use, including those of a verified grant.
+    /// Unknown peers without a grant get none. Access to a named service follows access
+    /// to its port, and access to part of an exposed range allows only that part.
+    /// Destination rules and ports to listen on apply only to peers allowed every port.
+    pub(crate) fn allowed_ports(&self, peer: &EndpointId, grant: Option<&Grant>) -> AllowedPorts {
+        let access = self.authorized.access(peer);
+        let exposures = self.exposed.iter().flat_map(|exposure| {
+            let mut ports = access.map_or_else(Vec::new, |access| access.permitted(&exposure.port));
+            if let Some(grant) = grant {
+                ports.extend(
+                    grant
+                        .ports
+                        .iter()
+                        .filter_map(|granted| exposure.port.intersect(granted)),
+                );
+            }
+            ports.into_iter().map(|port| Exposure {
+                name: exposure.name.clone(),
+                port,
+            })
+        });
+        let mut allowed = AllowedPorts::from_exposures(exposures);
+        if access == Some(&Access::All) {
+            allowed.dest = Arc::new(self.dest_rules.clone());
+            allowed.bind = Arc::new(self.bind_ports.clone());
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn peer() -> EndpointId {
+        SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    fn specs(specs: &[&str]) -> Vec<PortSpec> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    fn targets(targets: &[&str]) -> TargetSet {
+        targets
+            .iter()
+            .map(|target| target.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn allowed_ports_are_resolved_per_peer() {
+        let (ops, contractor, stranger) = (peer(), peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            ops,
+            Access::Ports(specs(&["22", "5432", "53/udp"]).into_iter().collect()),
+        );
+        authorized.grant(
+            contractor,
+            Access::Ports(specs(&["8080"]).into_iter().collect()),
+        );
+        let policy = Policy::new(specs(&["22", "5432", "8080", "53/udp"]), authorized);
+
+        let allowed = policy.allowed_ports(&ops, None);
+        assert_eq!(*allowed.tcp, targets(&["22", "5432"]));
+        assert_eq!(*allowed.udp, targets(&["53"]));
+
+        let allowed = policy.allowed_ports(&contractor, None);
+        assert_eq!(*allowed.tcp, targets(&["8080"]));
+        assert!(allowed.udp.is_empty());
+
+        assert!(!policy.accepts(&stranger));
+        assert!(policy.allowed_ports(&stranger, None).tcp.is_empty());
+    }
+
+    #[test]
+    fn granted_ports_that_are_not_exposed_are_ignored() {
+        let a = peer();
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            a,
+            Access::Ports(specs(&["22", "9000"]).into_iter().collect()),
+        );
+        let policy = Policy::new(specs(&["22"]), authorized);
+
+        assert_eq!(*policy.allowed_ports(&a, None).tcp, targets(&["22"]));
+    }
+
+    #[test]
+    fn targets_on_other_hosts_are_matched_in_full() {
+        let a = peer();
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            a,
+            Access::Ports(specs(&["db.lan:5432", "53/udp"]).into_iter().collect()),
+        );
+        let exposed: Vec<Exposure> = ["db=db.lan:5432", "5432", "10.0.0.2:53/udp", "53/udp"]
+            .iter()
+            .map(|exposure| exposure.parse().unwrap())
+            .collect();
+        let policy = Policy::new(exposed, authorized);
+
+        let allowed = policy.allowed_ports(&a, None);
+        assert_eq!(*allowed.tcp, targets(&["db.lan:5432"]));
+        assert_eq!(*allowed.udp, targets(&["53"]));
+        assert_eq!(
+            allowed.tcp_target(&Remote::Service("db".into())),
+            Some("db.lan:5432".parse().unwrap())
+        );
+        assert_eq!(
+            allowed.tcp_target(&Remote::Target(Target::loopback(5432))),
+            None
+        );
+    }
+
+    #[test]
+    fn unix_sockets_follow_access_to_the_socket() {
+        let (a, b) = (peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            a,
+            Access::Ports(specs(&["unix:/var/run/docker.sock"]).into_iter().collect()),
+        );
+        authorized.grant(b, Access::Ports(specs(&["22"]).into_iter().collect()));
+        let exposed: Vec<Exposure> = ["docker=unix:/var/run/docker.sock", "22"]
+            .iter()
+            .map(|exposure| exposure.parse().unwrap())
+            .collect();
+        let policy = Policy::new(exposed, authorized);
+
+        let docker = Remote::Service("docker".into());
+        let allowed = policy.allowed_ports(&a, None);
+        assert!(allowed.tcp.is_empty());
+        assert_eq!(
+            allowed.tcp_target(&docker),
+            Some("unix:/var/run/docker.sock".parse().unwrap())
+        );
+        assert_eq!(policy.allowed_ports(&b, None).tcp_target(&docker), None);
+    }
+
+    #[test]
+    fn offers_list_only_what_the_peer_may_use() {
+        let (a, b) = (peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(a, Access::All);
+        authorized.grant(
+            b,
+            Access::Ports(specs(&["22", "10050-10060/udp"]).into_iter().collect()),
+        );
+        let exposed: Vec<Exposure> = ["ssh=22", "8080", "db.lan:5432", "10000-10100/udp"]
+            .iter()
+            .map(|exposure| exposure.parse().unwrap())
+            .collect();
+        let policy = Policy::new(exposed, authorized)
+            .with_dest_rules(vec!["10.0.0.0/24:5432".parse().unwrap()])
+            .with_bind_ports(vec!["9000-9100".parse().unwrap()]);
+
+        let offer = policy.allowed_ports(&a, None).offer();
+        assert_eq!(
+            offer.to_string(),
+            "22/tcp\n8080/tcp\ndb.lan:5432/tcp\n10000-10100/udp\nssh\ndest:10.0.0.0/24:5432/tcp\nbind:9000-9100\n"
+        );
+        let offer = policy.allowed_ports(&b, None).offer();
+        assert_eq!(offer.to_string(), "22/tcp\n10050-10060/udp\nssh\n");
+    }
+
+    #[test]
+    fn open_policies_refuse_rules_for_every_peer() {
+        let rule: DestRule = "10.0.0.0/8:5432".parse().unwrap();
+        let open = Policy::new(Vec::<Exposure>::new(), AuthorizedPeers::any());
+        assert!(open.check_open_rules().is_ok());
+        let err = open
+            .clone()
+            .with_dest_rules(vec![rule.clone()])
+            .check_open_rules()
+            .unwrap_err();
+        assert!(err.to_string().contains("--allow-dest"), "{err}");
+        assert!(
+            open.with_bind_ports(vec![PortRange::single(9000)])
+                .check_open_rules()
+                .is_err()
+        );
+
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(peer(), Access::All);
+        let restricted = Policy::new(Vec::<Exposure>::new(), authorized)
+            .with_dest_rules(vec![rule])
+            .with_bind_ports(vec![PortRange::single(9000)]);
+        assert!(restricted.check_open_rules().is_ok());
+    }
+
+    #[test]
+    fn target_sets_merge_port_ranges() {
+        let set = targets(&["10000-10049", "10050-10100", "10020", "db.lan:5432", "9000"]);
+        assert_eq!(set, targets(&["9000", "10000-10100", "db.lan:5432"]));
+        assert!(set.contains(&"10000".parse().unwrap()));
+        assert!(set.contains(&"10100".parse().unwrap()));
+        assert!(set.contains(&"10010-10090".parse().unwrap()));
+        assert!(set.contains(&"9000".parse().unwrap()));
+        assert!(!set.contains(&"9999".parse().unwrap()));
+        assert!(!set.contains(&"10101".parse().unwrap()));
+        assert!(!set.contains(&"9000-10000".parse().unwrap()));
+        assert!(!set.contains(&"db.lan:5433".parse().unwrap()));
+        assert!(!set.contains(&"unix:/tmp/db.sock".parse().unwrap()));
+    }
+
+    #[test]
+    fn access_to_part_of_a_range_allows_only_that_part() {
+        let (a, b) = (peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            a,
+            Access::Ports(specs(&["10050-10200/udp", "22"]).into_iter().collect()),
+        );
+        authorized.grant(b, Access::All);
+        let policy = Policy::new(specs(&["10000-10100/udp", "20000-20010"]), authorized);
+
+        let allowed = policy.allowed_ports(&a, None);
+        assert_eq!(*allowed.udp, targets(&["10050-10100"]));
+        assert!(allowed.tcp.is_empty());
+
+        let allowed = policy.allowed_ports(&b, None);
+        assert_eq!(*allowed.udp, targets(&["10000-10100"]));
+        assert_eq!(*allowed.tcp, targets(&["20000-20010"]));
+    }
+
+    #[test]
+    fn destination_rules_need_access_to_every_port() {
+        let (a, b) = (peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(a, Access::All);
+        authorized.grant(b, Access::Ports(specs(&["22"]).into_iter().collect()));
+        let rules: Vec<DestRule> = vec!["10.0.0.0/24:5432".parse().unwrap()];
+        let policy = Policy::new(specs(&["22"]), authorized).with_dest_rules(rules.clone());
+
+        assert_eq!(*policy.allowed_ports(&a, None).dest, rules);
+        assert!(policy.allowed_ports(&b, None).dest.is_empty());
+    }
+
+    #[test]
+    fn verified_grants_add_exposed_ports() {
+        let issuer = SecretKey::generate(&mut rand::rng());
+        let (contractor, stranger) = (peer(), peer());
+        let mut authorized = AuthorizedPeers::default();
+        authorized.grant(
+            contractor,
+            Access::Ports(specs(&["8080"]).into_iter().collect()),
+        );
+        let policy = Policy::new(specs(&["22", "5432", "8080"]), authorized)
+            .with_grant_issuer(issuer.public());
+
+        let token = Grant::sign(&issuer, contractor, specs(&["22", "9000"]), u64::MAX).unwrap();
+        let grant = policy.verify_grant(&token, &contractor).unwrap();
+        let allowed = policy.allowed_ports(&contractor, Some(&grant));
+        assert_eq!(*allowed.tcp, targets(&["22", "8080"]));
+
+        assert!(policy.verify_grant(&token, &stranger).is_err());
+        let token = Grant::sign(&issuer, stranger, specs(&["5432"]), u64::MAX).unwrap();
+        let grant = policy.verify_grant(&token, &stranger).unwrap();
+        let allowed = policy.allowed_ports(&stranger, Some(&grant));
+        assert_eq!(*allowed.tcp, targets(&["5432"]));
+    }
+
+    #[test]
+    fn grants_from_other_issuers_are_rejected() {
+        let a = peer();
+        let token = Grant::sign(
+            &SecretKey::generate(&mut rand::rng()),
+            a,
+            specs(&["22"]),
+            u64::MAX,
+        )
+        .unwrap();
+
+        let policy = Policy::new(specs(&["22"]), AuthorizedPeers::default());
+        assert!(policy.verify_grant(&token, &a).is_err());
+        let policy = policy.with_grant_issuer(peer());
+        assert!(policy.verify_grant(&token, &a).is_err());
+    }
+}
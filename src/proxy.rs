@@ -1,15 +1,13 @@
 use anyhow::Result;
 use iroh::endpoint::{RecvStream, SendStream};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
 
-pub async fn bidirectional(
-    mut send: SendStream,
-    mut recv: RecvStream,
-    tcp: TcpStream,
-) -> Result<()> {
-    let (mut tcp_read, mut tcp_write) = tcp.into_split();
-    bridge(&mut send, &mut recv, &mut tcp_read, &mut tcp_write).await
+pub async fn bidirectional<S>(mut send: SendStream, mut recv: RecvStream, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut local_read, mut local_write) = tokio::io::split(stream);
+    bridge(&mut send, &mut recv, &mut local_read, &mut local_write).await
 }
 
 pub async fn bridge<R, W>(
@@ -0,0 +1,143 @@
+use iroh::endpoint::{ReadError, VarInt, WriteError};
+use std::fmt;
+use std::io;
+
+/// Why `punch out` reset a stream, sent as the stream's application error code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Any other failure. Servers that predate these codes send it for every failure.
+    Failed,
+    /// The target is not exposed to this peer.
+    NotAllowed,
+    ConnectionRefused,
+    HostUnreachable,
+    TimedOut,
+    /// The peer opened streams faster than the server allows.
+    RateLimited,
+}
+
+impl Rejection {
+    pub fn code(self) -> VarInt {
+        let code: u32 = match self {
+            Rejection::Failed => 1,
+            Rejection::NotAllowed => 2,
+            Rejection::ConnectionRefused => 3,
+            Rejection::HostUnreachable => 4,
+            Rejection::TimedOut => 5,
+            Rejection::RateLimited => 6,
+        };
+        code.into()
+    }
+
+    /// Reads a code sent by the server. Unknown codes from newer servers are failures.
+    pub fn from_code(code: VarInt) -> Self {
+        match code.into_inner() {
+            2 => Rejection::NotAllowed,
+            3 => Rejection::ConnectionRefused,
+            4 => Rejection::HostUnreachable,
+            5 => Rejection::TimedOut,
+            6 => Rejection::RateLimited,
+            _ => Rejection::Failed,
+        }
+    }
+
+    /// The rejection for a failure to reach the target.
+    pub fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            // A Unix socket that does not exist has nothing listening, like a closed port.
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound => {
+                Rejection::ConnectionRefused
+            }
+            io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable => Rejection::HostUnreachable,
+            io::ErrorKind::TimedOut => Rejection::TimedOut,
+            _ => Rejection::Failed,
+        }
+    }
+
+    /// Finds the rejection behind an error from proxying a stream, if the server reset it.
+    pub fn from_error(error: &anyhow::Error) -> Option<Self> {
+        let code = match error.downcast_ref() {
+            Some(ReadError::Reset(code)) => *code,
+            _ => match error.downcast_ref() {
+                Some(WriteError::Stopped(code)) => *code,
+                _ => return None,
+            },
+        };
+        Some(Self::from_code(code))
+    }
+
+    /// The exit code of `punch in` when its stdio stream is rejected.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Rejection::Failed => 1,
+            Rejection::NotAllowed => 3,
+            Rejection::ConnectionRefused => 4,
+            Rejection::HostUnreachable => 5,
+            Rejection::TimedOut => 6,
+            Rejection::RateLimited => 7,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::Failed => "remote peer could not open the stream",
+            Rejection::NotAllowed => "not allowed by the remote peer",
+            Rejection::ConnectionRefused => "connection refused on the remote peer",
+            Rejection::HostUnreachable => "host unreachable from the remote peer",
+            Rejection::TimedOut => "timed out connecting from the remote peer",
+            Rejection::RateLimited => "rate limited by the remote peer",
+        })
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Rejection; 6] = [
+        Rejection::Failed,
+        Rejection::NotAllowed,
+        Rejection::ConnectionRefused,
+        Rejection::HostUnreachable,
+        Rejection::TimedOut,
+        Rejection::RateLimited,
+    ];
+
+    #[test]
+    fn codes_roundtrip() {
+        for rejection in ALL {
+            assert_eq!(Rejection::from_code(rejection.code()), rejection);
+        }
+        assert_eq!(Rejection::from_code(99u32.into()), Rejection::Failed);
+        assert_eq!(Rejection::from_code(0u32.into()), Rejection::Failed);
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let mut codes: Vec<u8> = ALL.iter().map(|rejection| rejection.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), ALL.len());
+        assert!(!codes.contains(&2), "2 is used for usage errors");
+    }
+
+    #[test]
+    fn resets_are_found_behind_context() {
+        let error =
+            anyhow::Error::from(ReadError::Reset(Rejection::TimedOut.code())).context("9999/tcp");
+        assert_eq!(Rejection::from_error(&error), Some(Rejection::TimedOut));
+        let error = anyhow::Error::from(WriteError::Stopped(Rejection::NotAllowed.code()));
+        assert_eq!(Rejection::from_error(&error), Some(Rejection::NotAllowed));
+        assert_eq!(Rejection::from_error(&anyhow::anyhow!("other")), None);
+        assert_eq!(
+            Rejection::from_io(&io::Error::from(io::ErrorKind::ConnectionRefused)),
+            Rejection::ConnectionRefused
+        );
+    }
+}
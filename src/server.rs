@@ -1,62 +1,124 @@
-use crate::parse::{PortSpec, Protocol};
+use crate::authorized;
+use crate::control::{self, ALPN_V0, ALPN_V1, Control, Hello};
+use crate::dest::{self, DestRule};
+use crate::grant::{self, GRANT_ALPN, GRANT_ALPN_V1, Grant};
+use crate::header;
+use crate::invite::{self, Invites, PAIR_ALPN};
+use crate::parse::{LOOPBACK, Protocol, Remote, Target, UnixSocket};
+use crate::policy::{AllowedPorts, Policy};
 use crate::proxy;
+use crate::rejection::Rejection;
+use crate::stream;
 use crate::udp;
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use iroh::endpoint::presets;
 use iroh::endpoint::{Connection, Incoming, RecvStream, SendStream};
-use iroh::{Endpoint, SecretKey};
+use iroh::{Endpoint, EndpointId, SecretKey};
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{Mutex, oneshot};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixStream, lookup_host};
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::{Mutex, oneshot, watch};
 use tokio::task::JoinSet;
 
-const ALPN: &[u8] = b"punch/0";
-
-#[derive(Clone, Debug)]
-pub(crate) struct AllowedPorts {
-    tcp: Arc<HashSet<u16>>,
-    udp: Arc<HashSet<u16>>,
+pub(crate) const UNAUTHORIZED_CLOSE_CODE: u32 = 1;
+const UNAUTHORIZED_CLOSE_REASON: &[u8] = b"unauthorized";
+/// How long a peer connecting with a grant has to present it.
+const GRANT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a `punch/1` peer has to open the control stream.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a peer has to present an invitation code. Pairing needs no authorization,
+/// so the exchange must not hold a task for longer.
+const PAIR_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many ports one connection may have `punch out` listen on for `--reverse`.
+const MAX_REVERSE_LISTENERS: usize = 16;
+/// How many streams a connection may open per second, on average.
+const STREAMS_PER_SECOND: f64 = 100.0;
+/// How many streams a connection may open in a burst before it is limited.
+const STREAM_BURST: f64 = 200.0;
+
+/// State shared by every connection accepted by `punch out`.
+pub(crate) struct ServerState {
+    policy: watch::Sender<Arc<Policy>>,
+    invites: std::sync::Mutex<Invites>,
+    /// Peers paired during this run, kept across reloads.
+    paired: std::sync::Mutex<Vec<EndpointId>>,
 }
 
-impl AllowedPorts {
-    pub(crate) fn from_ports(ports: &[PortSpec]) -> Self {
-        let tcp = ports
-            .iter()
-            .filter(|port| port.protocol == Protocol::Tcp)
-            .map(|port| port.port())
-            .collect();
-        let udp = ports
-            .iter()
-            .filter(|port| port.protocol == Protocol::Udp)
-            .map(|port| port.port())
-            .collect();
-
+impl ServerState {
+    pub(crate) fn new(policy: Policy, invites: Invites) -> Self {
         Self {
-            tcp: Arc::new(tcp),
-            udp: Arc::new(udp),
+            policy: watch::Sender::new(Arc::new(policy)),
+            invites: std::sync::Mutex::new(invites),
+            paired: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.borrow().clone()
+    }
+
+    /// Swaps in a new policy. Running connections apply it to new streams and datagrams.
+    pub(crate) fn reload(&self, mut policy: Policy) {
+        for peer in self.paired.lock().unwrap().iter() {
+            policy.authorize(*peer);
+        }
+        self.policy.send_replace(Arc::new(policy));
+    }
+
+    /// Authorizes `peer` for the rest of this run and records it in the authorized keys
+    /// file, if there is one.
+    fn authorize(&self, peer: EndpointId) -> Result<()> {
+        self.paired.lock().unwrap().push(peer);
+        self.policy
+            .send_modify(|policy| Arc::make_mut(policy).authorize(peer));
+        match self.policy().authorized().file() {
+            Some(file) => authorized::append(file, &peer, "paired with an invitation"),
+            None => {
+                eprintln!("warning: no authorized keys file, {peer} is authorized until exit");
+                Ok(())
+            }
         }
     }
 }
 
-pub async fn run(ports: Vec<PortSpec>, secret_key: SecretKey) -> Result<()> {
+/// Runs `punch out`. `reload` rebuilds the policy from its sources on SIGHUP.
+pub async fn run(
+    policy: Policy,
+    invites: Invites,
+    secret_key: SecretKey,
+    reload: impl Fn() -> Result<Policy> + Send + 'static,
+) -> Result<()> {
     let endpoint = Endpoint::builder(presets::N0)
         .secret_key(secret_key)
-        .alpns(vec![ALPN.to_vec()])
+        .alpns(alpns(!invites.is_empty()))
         .bind()
         .await?;
 
     eprintln!("public key: {}", endpoint.id());
 
-    let allowed = AllowedPorts::from_ports(&ports);
+    if policy.authorized().accepts_any() {
+        eprintln!("warning: no authorized peers configured, accepting any peer");
+    }
+
+    let id = endpoint.id();
+    let state = Arc::new(ServerState::new(policy.with_grant_issuer(id), invites));
+    // Installed here rather than in the task so a failure stops `run` instead of silently
+    // disabling reloads.
+    let hangup = signal(SignalKind::hangup()).context("failed to listen for SIGHUP")?;
+    tokio::spawn(reload_on_hangup(hangup, state.clone(), move || {
+        reload().map(|policy| policy.with_grant_issuer(id))
+    }));
 
     while let Some(incoming) = endpoint.accept().await {
-        let allowed = allowed.clone();
+        let state = state.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(incoming, allowed).await {
+            if let Err(e) = handle_connection(incoming, &state).await {
                 eprintln!("connection error: {e}");
             }
         });
@@ -65,36 +127,262 @@ pub async fn run(ports: Vec<PortSpec>, secret_key: SecretKey) -> Result<()> {
     Ok(())
 }
 
-async fn handle_connection(incoming: Incoming, allowed: AllowedPorts) -> Result<()> {
+/// The ALPNs `punch out` accepts. The first one the client also offers is chosen, so
+/// newer versions come first.
+pub(crate) fn alpns(pairing: bool) -> Vec<Vec<u8>> {
+    let mut alpns = vec![
+        ALPN_V1.to_vec(),
+        ALPN_V0.to_vec(),
+        GRANT_ALPN_V1.to_vec(),
+        GRANT_ALPN.to_vec(),
+    ];
+    if pairing {
+        alpns.push(PAIR_ALPN.to_vec());
+    }
+    alpns
+}
+
+async fn reload_on_hangup(
+    mut hangup: Signal,
+    state: Arc<ServerState>,
+    reload: impl Fn() -> Result<Policy>,
+) {
+    while hangup.recv().await.is_some() {
+        match reload() {
+            Ok(policy) => {
+                eprintln!("reloaded policy, exposing {}", policy.describe_exposed());
+                state.reload(policy);
+            }
+            Err(e) => eprintln!("reload failed, keeping the current policy: {e:#}"),
+        }
+    }
+}
+
+pub(crate) async fn handle_connection(incoming: Incoming, state: &ServerState) -> Result<()> {
     let conn = incoming.await?;
-    serve_connection(conn, allowed).await
+    if conn.alpn() == PAIR_ALPN {
+        let peer = conn.remote_id();
+        let paired = tokio::time::timeout(PAIR_TIMEOUT, handle_pairing(&conn, state)).await;
+        conn.close(0u32.into(), b"done");
+        return paired.with_context(|| format!("peer {peer} did not pair in time"))?;
+    }
+
+    let peer = conn.remote_id();
+    let policy = state.policy();
+    let grant = if conn.alpn() == GRANT_ALPN || conn.alpn() == GRANT_ALPN_V1 {
+        match tokio::time::timeout(GRANT_TIMEOUT, receive_grant(&conn, &policy)).await {
+            Ok(Ok(grant)) => Some(grant),
+            Ok(Err(e)) => {
+                conn.close(UNAUTHORIZED_CLOSE_CODE.into(), UNAUTHORIZED_CLOSE_REASON);
+                return Err(e.context(format!("rejected grant from peer {peer}")));
+            }
+            Err(_) => {
+                conn.close(UNAUTHORIZED_CLOSE_CODE.into(), UNAUTHORIZED_CLOSE_REASON);
+                bail!("peer {peer} did not present a grant in time");
+            }
+        }
+    } else {
+        None
+    };
+
+    if grant.is_none() && !policy.accepts(&peer) {
+        conn.close(UNAUTHORIZED_CLOSE_CODE.into(), UNAUTHORIZED_CLOSE_REASON);
+        bail!("rejected unauthorized peer {peer}");
+    }
+
+    let control = if conn.alpn() == ALPN_V1 || conn.alpn() == GRANT_ALPN_V1 {
+        let control = tokio::time::timeout(CONTROL_TIMEOUT, control::accept(&conn))
+            .await
+            .with_context(|| format!("peer {peer} did not open the control stream in time"))?
+            .with_context(|| format!("control stream with peer {peer} failed"))?;
+        Some(control)
+    } else {
+        None
+    };
+    serve_connection(conn, state.policy.subscribe(), grant, control).await
+}
+
+/// Reads the grant a peer presents on its first stream and reports the verdict back.
+async fn receive_grant(conn: &Connection, policy: &Policy) -> Result<Grant> {
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    let token = grant::read_token(&mut recv).await?;
+    match policy.verify_grant(&token, &conn.remote_id()) {
+        Ok(grant) => {
+            grant::reply(&mut send, true).await?;
+            Ok(grant)
+        }
+        Err(e) => {
+            grant::reply(&mut send, false).await?;
+            // Give the verdict a chance to arrive before the connection is closed.
+            let _ = send.stopped().await;
+            Err(e)
+        }
+    }
 }
 
-pub(crate) async fn serve_connection(conn: Connection, allowed: AllowedPorts) -> Result<()> {
+/// Redeems the invitation code a peer presents and reports the verdict back.
+async fn handle_pairing(conn: &Connection, state: &ServerState) -> Result<()> {
+    let peer = conn.remote_id();
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    let code = invite::read_code(&mut recv).await?;
+    let paired = state.invites.lock().unwrap().redeem(&code, Instant::now());
+
+    if !paired {
+        invite::reply(&mut send, false).await?;
+        let _ = send.stopped().await;
+        bail!("rejected invitation from peer {peer}");
+    }
+
+    eprintln!("paired peer {peer}");
+    let authorized = state.authorize(peer);
+    invite::reply(&mut send, true).await?;
+    // Give the verdict a chance to arrive before the connection is closed.
+    let _ = send.stopped().await;
+    authorized
+}
+
+/// Forwards the connection's streams and datagrams to the ports the policy allows.
+///
+/// The allowed ports are rebuilt whenever `policy` changes. Streams that are already
+/// proxied are not affected. `control` is the control stream of a `punch/1` connection,
+/// whose requests are answered until the connection ends.
+pub(crate) async fn serve_connection(
+    conn: Connection,
+    policy: watch::Receiver<Arc<Policy>>,
+    grant: Option<Grant>,
+    control: Option<Control>,
+) -> Result<()> {
+    let negotiated = control
+        .as_ref()
+        .map_or(Hello::V0, |control| control.negotiated);
+    let peer = conn.remote_id();
+    let (allowed_tx, allowed) =
+        watch::channel(policy.borrow().allowed_ports(&peer, grant.as_ref()));
     let mut tasks = JoinSet::new();
 
-    let tcp_allowed = allowed.tcp.clone();
+    tasks.spawn(async move { track_policy(policy, peer, grant, allowed_tx).await });
+
+    if let Some(mut control) = control {
+        let allowed = allowed.clone();
+        let conn = conn.clone();
+        tasks.spawn(async move {
+            let listeners = Arc::new(AtomicUsize::new(0));
+            let bind = |port| bind_reverse(conn.clone(), allowed.clone(), listeners.clone(), port);
+            control.serve(allowed.clone(), bind).await?;
+            // The client has nothing more to ask, but its streams keep being served.
+            std::future::pending().await
+        });
+    }
+
+    let tcp_allowed = allowed.clone();
     let tcp_conn = conn.clone();
     tasks.spawn(async move { run_tcp_accept_loop(tcp_conn, tcp_allowed).await });
 
-    if !allowed.udp.is_empty() {
-        let state = Arc::new(Mutex::new(ServerUdpState::default()));
+    let state = Arc::new(Mutex::new(ServerUdpState::default()));
+    let udp_conn = conn.clone();
+    let udp_state = state.clone();
+    let max_payload = usize::from(negotiated.max_datagram_size);
+    tasks.spawn(async move { run_udp_datagrams(udp_conn, allowed, udp_state, max_payload).await });
+    tasks.spawn(async move { run_udp_cleanup(state).await });
 
-        let udp_conn = conn.clone();
-        let udp_allowed = allowed.udp.clone();
-        let udp_state = state.clone();
-        tasks.spawn(async move { run_udp_datagrams(udp_conn, udp_allowed, udp_state).await });
+    supervise_tasks(conn.closed(), tasks).await
+}
 
-        tasks.spawn(async move { run_udp_cleanup(state).await });
+/// Recomputes the connection's allowed ports each time the policy is reloaded.
+async fn track_policy(
+    mut policy: watch::Receiver<Arc<Policy>>,
+    peer: EndpointId,
+    grant: Option<Grant>,
+    allowed: watch::Sender<AllowedPorts>,
+) -> Result<()> {
+    while policy.changed().await.is_ok() {
+        let ports = policy
+            .borrow_and_update()
+            .allowed_ports(&peer, grant.as_ref());
+        allowed.send_replace(ports);
     }
+    // The policy can no longer change; keep serving with the last one.
+    std::future::pending().await
+}
 
-    supervise_tasks(conn.closed(), tasks).await
+/// One of the reverse listeners a connection may have, given back when dropped.
+struct ListenerSlot(Arc<AtomicUsize>);
+
+impl ListenerSlot {
+    fn take(listeners: &Arc<AtomicUsize>) -> Result<Self> {
+        let taken = listeners.fetch_add(1, Ordering::Relaxed);
+        let slot = Self(listeners.clone());
+        if taken >= MAX_REVERSE_LISTENERS {
+            bail!("at most {MAX_REVERSE_LISTENERS} ports may be listened on per connection");
+        }
+        Ok(slot)
+    }
+}
+
+impl Drop for ListenerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
-async fn run_tcp_accept_loop(conn: Connection, allowed: Arc<HashSet<u16>>) -> Result<()> {
+/// Listens on `127.0.0.1:port` for a `--reverse` peer, forwarding every connection
+/// accepted there over `conn` until the connection ends or the policy no longer allows
+/// the port. `listeners` counts the listeners of the connection.
+async fn bind_reverse(
+    conn: Connection,
+    allowed: watch::Receiver<AllowedPorts>,
+    listeners: Arc<AtomicUsize>,
+    port: u16,
+) -> Result<()> {
+    if !allowed.borrow().may_bind(port) {
+        bail!("listening on port {port} is not allowed");
+    }
+    let slot = ListenerSlot::take(&listeners)?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .await
+        .with_context(|| format!("failed to listen on port {port}"))?;
+    tokio::spawn(async move {
+        let _slot = slot;
+        tokio::select! {
+            result = run_reverse_listener(&conn, listener, allowed, port) => {
+                if let Err(e) = result {
+                    eprintln!("stopped listening on port {port}: {e:#}");
+                }
+            }
+            _ = conn.closed() => {}
+        }
+    });
+    Ok(())
+}
+
+async fn run_reverse_listener(
+    conn: &Connection,
+    listener: TcpListener,
+    allowed: watch::Receiver<AllowedPorts>,
+    port: u16,
+) -> Result<()> {
+    let remote = Remote::Target(Target::loopback(port));
     loop {
-        let (send, recv) = conn.accept_bi().await?;
-        let allowed = allowed.clone();
+        let (stream, _) = listener.accept().await?;
+        if !allowed.borrow().may_bind(port) {
+            bail!("the policy no longer allows it");
+        }
+        stream::spawn(conn, &remote, stream);
+    }
+}
+
+async fn run_tcp_accept_loop(
+    conn: Connection,
+    allowed: watch::Receiver<AllowedPorts>,
+) -> Result<()> {
+    let mut limiter = StreamLimiter::new(Instant::now());
+    loop {
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        if !limiter.try_acquire(Instant::now()) {
+            stream::reset(&mut send, &mut recv, Rejection::RateLimited);
+            continue;
+        }
+        let allowed = allowed.borrow().clone();
         tokio::spawn(async move {
             if let Err(e) = handle_stream(send, recv, allowed).await {
                 eprintln!("stream error: {e}");
@@ -103,34 +391,123 @@ async fn run_tcp_accept_loop(conn: Connection, allowed: Arc<HashSet<u16>>) -> Re
     }
 }
 
+/// Limits how fast a connection opens streams, as a token bucket.
+struct StreamLimiter {
+    tokens: f64,
+    last: Instant,
+}
+
+impl StreamLimiter {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: STREAM_BURST,
+            last: now,
+        }
+    }
+
+    /// Takes a token for a new stream, if one is left.
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * STREAMS_PER_SECOND).min(STREAM_BURST);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
 async fn handle_stream(
     mut send: SendStream,
     mut recv: RecvStream,
-    allowed: Arc<HashSet<u16>>,
+    allowed: AllowedPorts,
 ) -> Result<()> {
-    let mut port_buf = [0u8; 2];
-    recv.read_exact(&mut port_buf).await?;
-    let port = u16::from_be_bytes(port_buf);
-
-    if !allowed.contains(&port) {
-        reset_stream(&mut send, &mut recv);
-        bail!("port {port} not in expose list");
-    }
-
-    let tcp = match TcpStream::connect(("127.0.0.1", port)).await {
-        Ok(tcp) => tcp,
+    let remote = match header::read(&mut recv).await {
+        Ok(remote) => remote,
         Err(e) => {
-            reset_stream(&mut send, &mut recv);
-            return Err(e.into());
+            let rejection = if e.is::<header::PortRangeTarget>() {
+                Rejection::NotAllowed
+            } else {
+                Rejection::Failed
+            };
+            stream::reset(&mut send, &mut recv, rejection);
+            return Err(e);
         }
     };
-    proxy::bidirectional(send, recv, tcp).await
+
+    let Some(target) = allowed.tcp_target(&remote) else {
+        // A target that is not exposed may still be a destination the peer can choose.
+        let addrs = match remote {
+            Remote::Target(target) => dest::resolve(&allowed.dest, &target, Protocol::Tcp).await,
+            Remote::Service(name) => Err(anyhow!("service {name} not exposed")),
+        };
+        let addrs = match addrs {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                // Only failing to resolve the host carries an I/O error.
+                let rejection = match e.downcast_ref::<io::Error>() {
+                    Some(_) => Rejection::HostUnreachable,
+                    None => Rejection::NotAllowed,
+                };
+                stream::reset(&mut send, &mut recv, rejection);
+                return Err(e);
+            }
+        };
+        let connect = TcpStream::connect(&addrs[..]);
+        let tcp = stream::connect_or_reset(&mut send, &mut recv, connect).await?;
+        return proxy::bidirectional(send, recv, tcp).await;
+    };
+
+    match target {
+        Target::Inet { host, ports } => {
+            let host = host.as_deref().unwrap_or(LOOPBACK);
+            let addrs = match lookup_host((host, ports.first())).await {
+                Ok(addrs) => addrs.collect::<Vec<_>>(),
+                Err(e) => {
+                    stream::reset(&mut send, &mut recv, Rejection::HostUnreachable);
+                    return Err(anyhow!(e).context(format!("failed to resolve {host}")));
+                }
+            };
+            let connect = TcpStream::connect(&addrs[..]);
+            let tcp = stream::connect_or_reset(&mut send, &mut recv, connect).await?;
+            proxy::bidirectional(send, recv, tcp).await
+        }
+        Target::Unix(socket) => {
+            let unix =
+                stream::connect_or_reset(&mut send, &mut recv, connect_unix(&socket)).await?;
+            proxy::bidirectional(send, recv, unix).await
+        }
+    }
+}
+
+async fn connect_unix(socket: &UnixSocket) -> io::Result<UnixStream> {
+    match socket {
+        UnixSocket::Path(path) => UnixStream::connect(path).await,
+        UnixSocket::Abstract(name) => connect_abstract(name.clone()).await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn connect_abstract(name: String) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{self, SocketAddr};
+
+    let stream = tokio::task::spawn_blocking(move || {
+        let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+        net::UnixStream::connect_addr(&addr)
+    })
+    .await??;
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
 }
 
-fn reset_stream(send: &mut SendStream, recv: &mut RecvStream) {
-    let error_code = 1u32.into();
-    let _ = send.reset(error_code);
-    let _ = recv.stop(error_code);
+#[cfg(not(target_os = "linux"))]
+async fn connect_abstract(_name: String) -> io::Result<UnixStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract unix sockets are only supported on Linux",
+    ))
 }
 
 #[derive(Default)]
@@ -140,16 +517,40 @@ struct ServerUdpState {
 
 struct ServerUdpFlow {
     socket: Arc<UdpSocket>,
+    target: Target,
+    /// `target` resolved when the flow was created.
+    addr: SocketAddr,
     last_activity: Instant,
     send_error_logged: bool,
     shutdown: Option<oneshot::Sender<()>>,
 }
 
 impl ServerUdpState {
-    fn touch(&mut self, flow_id: u16, now: Instant) -> Option<Arc<UdpSocket>> {
+    /// Returns the socket and address of `flow_id` if it forwards to `target`. A flow
+    /// that switches target is replaced by a new one.
+    fn touch(
+        &mut self,
+        flow_id: u16,
+        target: &Target,
+        now: Instant,
+    ) -> Option<(Arc<UdpSocket>, SocketAddr)> {
         let flow = self.flows.get_mut(&flow_id)?;
+        if flow.target != *target {
+            return None;
+        }
         flow.last_activity = now;
-        Some(flow.socket.clone())
+        Some((flow.socket.clone(), flow.addr))
+    }
+
+    /// Adds a flow, stopping the reply task of the flow it replaces.
+    fn insert(&mut self, flow_id: u16, flow: ServerUdpFlow) {
+        if let Some(shutdown) = self
+            .flows
+            .insert(flow_id, flow)
+            .and_then(|mut old| old.shutdown.take())
+        {
+            let _ = shutdown.send(());
+        }
     }
 
     fn mark_send_error(&mut self, flow_id: u16, now: Instant) -> bool {
@@ -185,8 +586,9 @@ impl ServerUdpState {
 
 async fn run_udp_datagrams(
     conn: Connection,
-    allowed: Arc<HashSet<u16>>,
+    allowed: watch::Receiver<AllowedPorts>,
     state: Arc<Mutex<ServerUdpState>>,
+    max_payload: usize,
 ) -> Result<()> {
     loop {
         let datagram = conn.read_datagram().await?;
@@ -198,18 +600,42 @@ async fn run_udp_datagrams(
             }
         };
 
-        if !allowed.contains(&datagram.dest_port) {
-            continue;
-        }
+        // Targets that are not exposed are resolved against the destination rules when
+        // their flow is created.
+        let rules = {
+            let allowed = allowed.borrow();
+            if allowed.udp.contains(&datagram.dest) {
+                None
+            } else if allowed
+                .dest
+                .iter()
+                .any(|rule| rule.may_permit(&datagram.dest, Protocol::Udp))
+            {
+                Some(allowed.dest.clone())
+            } else {
+                continue;
+            }
+        };
 
-        let socket =
-            get_or_create_flow_socket(conn.clone(), state.clone(), datagram.flow_id).await?;
+        let (socket, addr) = match get_or_create_flow_socket(
+            conn.clone(),
+            state.clone(),
+            datagram.flow_id,
+            &datagram.dest,
+            rules.as_deref().map(Vec::as_slice),
+            max_payload,
+        )
+        .await
+        {
+            Ok(flow) => flow,
+            Err(e) => {
+                eprintln!("udp flow error for {}: {e:#}", datagram.dest);
+                continue;
+            }
+        };
         let now = Instant::now();
 
-        match socket
-            .send_to(datagram.payload, ("127.0.0.1", datagram.dest_port))
-            .await
-        {
+        match socket.send_to(datagram.payload, addr).await {
             Ok(_) => {
                 let mut state = state.lock().await;
                 if let Some(flow) = state.flows.get_mut(&datagram.flow_id) {
@@ -233,27 +659,44 @@ async fn get_or_create_flow_socket(
     conn: Connection,
     state: Arc<Mutex<ServerUdpState>>,
     flow_id: u16,
-) -> Result<Arc<UdpSocket>> {
+    target: &Target,
+    rules: Option<&[DestRule]>,
+    max_payload: usize,
+) -> Result<(Arc<UdpSocket>, SocketAddr)> {
     let now = Instant::now();
-    if let Some(socket) = {
+    if let Some(flow) = {
         let mut state = state.lock().await;
-        state.touch(flow_id, now)
+        state.touch(flow_id, target, now)
     } {
-        return Ok(socket);
+        return Ok(flow);
     }
 
-    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let addr = match rules {
+        Some(rules) => dest::resolve(rules, target, Protocol::Udp).await?[0],
+        None => {
+            let addr = target
+                .inet_addr()
+                .context("unix sockets cannot receive udp datagrams")?;
+            lookup_host(addr)
+                .await?
+                .next()
+                .context("host has no addresses")?
+        }
+    };
+    let socket = Arc::new(UdpSocket::bind(local_bind_addr(addr)).await?);
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
     {
         let mut state = state.lock().await;
-        if let Some(socket) = state.touch(flow_id, now) {
-            return Ok(socket);
+        if let Some(flow) = state.touch(flow_id, target, now) {
+            return Ok(flow);
         }
-        state.flows.insert(
+        state.insert(
             flow_id,
             ServerUdpFlow {
                 socket: socket.clone(),
+                target: target.clone(),
+                addr,
                 last_activity: now,
                 send_error_logged: false,
                 shutdown: Some(shutdown_tx),
@@ -263,10 +706,21 @@ async fn get_or_create_flow_socket(
 
     let reply_socket = socket.clone();
     tokio::spawn(async move {
-        run_flow_replies(conn, state, flow_id, reply_socket, shutdown_rx).await;
+        run_flow_replies(conn, state, flow_id, reply_socket, shutdown_rx, max_payload).await;
     });
 
-    Ok(socket)
+    Ok((socket, addr))
+}
+
+/// Binds flow sockets for loopback targets to loopback, and others to every interface.
+fn local_bind_addr(target: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match (target, target.ip().is_loopback()) {
+        (SocketAddr::V4(_), true) => Ipv4Addr::LOCALHOST.into(),
+        (SocketAddr::V4(_), false) => Ipv4Addr::UNSPECIFIED.into(),
+        (SocketAddr::V6(_), true) => Ipv6Addr::LOCALHOST.into(),
+        (SocketAddr::V6(_), false) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, 0)
 }
 
 async fn run_flow_replies(
@@ -275,6 +729,7 @@ async fn run_flow_replies(
     flow_id: u16,
     socket: Arc<UdpSocket>,
     mut shutdown_rx: oneshot::Receiver<()>,
+    max_payload: usize,
 ) {
     let mut buf = [0u8; udp::MAX_UDP_PACKET_SIZE];
 
@@ -287,18 +742,25 @@ async fn run_flow_replies(
                     let now = Instant::now();
                     let active = {
                         let mut state = state.lock().await;
+                        // The flow may have been replaced by one to another target.
                         match state.flows.get_mut(&flow_id) {
-                            Some(flow) => {
+                            Some(flow) if Arc::ptr_eq(&flow.socket, &socket) => {
                                 flow.last_activity = now;
                                 true
                             }
-                            None => false,
+                            _ => false,
                         }
                     };
 
                     if !active {
                         break;
                     }
+                    if len > max_payload {
+                        eprintln!(
+                            "udp reply of {len} bytes exceeds the peer's limit of {max_payload} bytes"
+                        );
+                        continue;
+                    }
 
                     if let Err(e) = udp::send_server_datagram(&conn, flow_id, &buf[..len]) {
                         eprintln!("udp datagram error: {e}");
@@ -337,9 +799,14 @@ async fn supervise_tasks(conn_closed: impl Future, mut tasks: JoinSet<Result<()>
 
 #[cfg(test)]
 mod tests {
-    use super::{ServerUdpFlow, ServerUdpState};
+    use super::{
+        ListenerSlot, MAX_REVERSE_LISTENERS, STREAM_BURST, STREAMS_PER_SECOND, ServerUdpFlow,
+        ServerUdpState, StreamLimiter, connect_unix,
+    };
+    use crate::parse::Target;
     use crate::udp;
     use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
     use std::time::Instant;
     use tokio::net::UdpSocket;
     use tokio::sync::oneshot;
@@ -354,7 +821,9 @@ mod tests {
         state.flows.insert(
             1,
             ServerUdpFlow {
+                addr: socket.local_addr().unwrap(),
                 socket,
+                target: Target::loopback(53),
                 last_activity: now - udp::FLOW_IDLE_TIMEOUT - std::time::Duration::from_secs(1),
                 send_error_logged: false,
                 shutdown: Some(shutdown_tx),
@@ -365,4 +834,80 @@ mod tests {
         assert_eq!(shutdowns.len(), 1);
         assert!(state.flows.is_empty());
     }
+
+    #[test]
+    fn stream_limiter_allows_bursts_then_refills() {
+        let start = Instant::now();
+        let mut limiter = StreamLimiter::new(start);
+        for _ in 0..STREAM_BURST as usize {
+            assert!(limiter.try_acquire(start));
+        }
+        assert!(!limiter.try_acquire(start));
+
+        let later = start + std::time::Duration::from_secs_f64(2.0 / STREAMS_PER_SECOND);
+        assert!(limiter.try_acquire(later));
+        assert!(limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+    }
+
+    #[test]
+    fn reverse_listeners_are_capped_per_connection() {
+        let listeners = Arc::new(AtomicUsize::new(0));
+        let mut slots: Vec<ListenerSlot> = (0..MAX_REVERSE_LISTENERS)
+            .map(|_| ListenerSlot::take(&listeners).unwrap())
+            .collect();
+        let err = ListenerSlot::take(&listeners).err().unwrap();
+        assert!(err.to_string().contains("at most"), "{err}");
+
+        slots.pop();
+        assert!(ListenerSlot::take(&listeners).is_ok());
+    }
+
+    #[tokio::test]
+    async fn replacing_a_flow_shuts_down_the_old_one() {
+        let now = Instant::now();
+        let mut state = ServerUdpState::default();
+        let mut shutdowns = Vec::new();
+        for port in [53, 5353] {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+            shutdowns.push(shutdown_rx);
+            state.insert(
+                1,
+                ServerUdpFlow {
+                    addr: socket.local_addr().unwrap(),
+                    socket,
+                    target: Target::loopback(port),
+                    last_activity: now,
+                    send_error_logged: false,
+                    shutdown: Some(shutdown_tx),
+                },
+            );
+        }
+
+        assert_eq!(shutdowns[0].try_recv(), Ok(()));
+        assert!(shutdowns[1].try_recv().is_err());
+        assert!(state.touch(1, &Target::loopback(53), now).is_none());
+        assert!(state.touch(1, &Target::loopback(5353), now).is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abstract_unix_sockets_connect() {
+        use crate::parse::UnixSocket;
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+
+        let name = format!("punch-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = UnixListener::bind_addr(&addr).unwrap();
+
+        connect_unix(&UnixSocket::Abstract(name)).await.unwrap();
+        listener.accept().unwrap();
+        assert!(
+            connect_unix(&UnixSocket::Abstract("punch-test-missing".into()))
+                .await
+                .is_err()
+        );
+    }
 }
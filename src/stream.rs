@@ -0,0 +1,91 @@
+use crate::header;
+use crate::parse::Remote;
+use crate::proxy;
+use crate::rejection::Rejection;
+use anyhow::{Result, bail};
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+
+/// How long connecting to a target may take before the stream is rejected.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection accepted by a local listener.
+pub trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Closes the connection the way the target refusing it would look locally.
+    fn refuse(self);
+}
+
+impl LocalStream for TcpStream {
+    fn refuse(self) {
+        // A zero linger does not block: closing sends a RST instead of a FIN.
+        #[allow(deprecated)]
+        let _ = self.set_linger(Some(Duration::ZERO));
+    }
+}
+
+impl LocalStream for UnixStream {
+    fn refuse(self) {}
+}
+
+/// Forwards `stream` to `remote` on the peer, refusing it locally if the peer rejects it.
+pub fn spawn<S: LocalStream>(conn: &Connection, remote: &Remote, mut stream: S) {
+    let conn = conn.clone();
+    let remote = remote.clone();
+    tokio::spawn(async move {
+        if let Err(e) = forward(conn, &remote, &mut stream).await {
+            eprintln!("stream error: {e:#}");
+            if e.is::<Rejection>() {
+                stream.refuse();
+            }
+        }
+    });
+}
+
+async fn forward<S>(conn: Connection, remote: &Remote, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut send, recv) = conn.open_bi().await?;
+    header::write(&mut send, remote).await?;
+    proxy::bidirectional(send, recv, stream)
+        .await
+        .map_err(|e| explain_rejection(e, remote))
+}
+
+/// Replaces the reset of a stream the remote peer rejected with the reason it gave.
+pub fn explain_rejection(error: anyhow::Error, remote: &Remote) -> anyhow::Error {
+    match Rejection::from_error(&error) {
+        Some(rejection) => anyhow::Error::new(rejection).context(remote.to_string()),
+        None => error,
+    }
+}
+
+/// Waits for `connect`, resetting the stream with the reason it failed or timed out.
+pub async fn connect_or_reset<S>(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    connect: impl Future<Output = io::Result<S>>,
+) -> Result<S> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => {
+            reset(send, recv, Rejection::from_io(&e));
+            Err(e.into())
+        }
+        Err(_) => {
+            reset(send, recv, Rejection::TimedOut);
+            bail!("timed out connecting to the target");
+        }
+    }
+}
+
+/// Rejects a stream, telling the peer why with the error code.
+pub fn reset(send: &mut SendStream, recv: &mut RecvStream, rejection: Rejection) {
+    let error_code = rejection.code();
+    let _ = send.reset(error_code);
+    let _ = recv.stop(error_code);
+}
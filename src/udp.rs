@@ -1,3 +1,5 @@
+use crate::header;
+use crate::parse::Target;
 use anyhow::{Context, Result, bail};
 use iroh::endpoint::{Connection, SendDatagramError};
 use std::time::{Duration, Instant};
@@ -9,10 +11,10 @@ pub const MAX_UDP_PACKET_SIZE: usize = 65_535;
 const CLIENT_HEADER_LEN: usize = 4;
 const SERVER_HEADER_LEN: usize = 2;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ClientDatagram<'a> {
     pub flow_id: u16,
-    pub dest_port: u16,
+    pub dest: Target,
     pub payload: &'a [u8],
 }
 
@@ -22,12 +24,22 @@ pub struct ServerDatagram<'a> {
     pub payload: &'a [u8],
 }
 
-pub fn encode_client_datagram(flow_id: u16, dest_port: u16, payload: &[u8]) -> Vec<u8> {
+/// Encodes `[flow_id][dest_port][payload]`. Targets on other hosts use port 0 followed
+/// by a 1-byte length and `host:port`, as in the stream header.
+pub fn encode_client_datagram(flow_id: u16, dest: &Target, payload: &[u8]) -> Result<Vec<u8>> {
     let mut datagram = Vec::with_capacity(CLIENT_HEADER_LEN + payload.len());
     datagram.extend_from_slice(&flow_id.to_be_bytes());
-    datagram.extend_from_slice(&dest_port.to_be_bytes());
+    match dest {
+        Target::Inet { host: None, ports } if ports.single_port().is_some() => {
+            datagram.extend_from_slice(&ports.first().to_be_bytes())
+        }
+        Target::Inet { .. } => {
+            datagram.extend_from_slice(&header::encode_named(&dest.to_string())?)
+        }
+        Target::Unix(_) => bail!("unix sockets cannot receive udp datagrams"),
+    }
     datagram.extend_from_slice(payload);
-    datagram
+    Ok(datagram)
 }
 
 pub fn decode_client_datagram(datagram: &[u8]) -> Result<ClientDatagram<'_>> {
@@ -35,10 +47,27 @@ pub fn decode_client_datagram(datagram: &[u8]) -> Result<ClientDatagram<'_>> {
         bail!("client datagram too short");
     }
 
+    let flow_id = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dest_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    if dest_port != header::NAMED_PORT {
+        return Ok(ClientDatagram {
+            flow_id,
+            dest: Target::loopback(dest_port),
+            payload: &datagram[CLIENT_HEADER_LEN..],
+        });
+    }
+
+    let len = *datagram
+        .get(CLIENT_HEADER_LEN)
+        .context("client datagram too short")? as usize;
+    let start = CLIENT_HEADER_LEN + 1;
+    let name = datagram
+        .get(start..start + len)
+        .context("client datagram too short")?;
     Ok(ClientDatagram {
-        flow_id: u16::from_be_bytes([datagram[0], datagram[1]]),
-        dest_port: u16::from_be_bytes([datagram[2], datagram[3]]),
-        payload: &datagram[CLIENT_HEADER_LEN..],
+        flow_id,
+        dest: header::single_port(header::decode_name(name)?.parse()?)?,
+        payload: &datagram[start + len..],
     })
 }
 
@@ -75,10 +104,10 @@ fn ensure_datagram_fits(limit: Option<usize>, datagram_len: usize) -> Result<()>
 pub fn send_client_datagram(
     conn: &Connection,
     flow_id: u16,
-    dest_port: u16,
+    dest: &Target,
     payload: &[u8],
 ) -> Result<()> {
-    let datagram = encode_client_datagram(flow_id, dest_port, payload);
+    let datagram = encode_client_datagram(flow_id, dest, payload)?;
     send_datagram(conn, datagram)
 }
 
@@ -113,13 +142,30 @@ mod tests {
 
     #[test]
     fn client_datagram_roundtrip() {
-        let datagram = encode_client_datagram(7, 53, b"hello");
+        let datagram = encode_client_datagram(7, &Target::loopback(53), b"hello").unwrap();
         let decoded = decode_client_datagram(&datagram).unwrap();
         assert_eq!(decoded.flow_id, 7);
-        assert_eq!(decoded.dest_port, 53);
+        assert_eq!(decoded.dest, Target::loopback(53));
         assert_eq!(decoded.payload, b"hello");
     }
 
+    #[test]
+    fn client_datagram_to_host_roundtrip() {
+        let dest: Target = "[fd00::53]:53".parse().unwrap();
+        let datagram = encode_client_datagram(3, &dest, b"query").unwrap();
+        let decoded = decode_client_datagram(&datagram).unwrap();
+        assert_eq!(decoded.flow_id, 3);
+        assert_eq!(decoded.dest, dest);
+        assert_eq!(decoded.payload, b"query");
+
+        assert!(decode_client_datagram(&datagram[..6]).is_err());
+
+        let range: Target = "db.lan:5000-5001".parse().unwrap();
+        let datagram = encode_client_datagram(3, &range, b"query").unwrap();
+        let err = decode_client_datagram(&datagram).unwrap_err();
+        assert!(err.is::<header::PortRangeTarget>(), "{err}");
+    }
+
     #[test]
     fn server_datagram_roundtrip() {
         let datagram = encode_server_datagram(9, b"world");